@@ -0,0 +1,161 @@
+use git2::Repository;
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Handles kept open per repository between requests.
+const POOL_SIZE: usize = 4;
+
+lazy_static! {
+    static ref POOL: Mutex<HashMap<String, Vec<Repository>>> = Mutex::new(HashMap::new());
+}
+
+/// A least recently used cache holding at most `capacity` entries, counting
+/// hits and misses.
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+
+        match self.entries.get_mut(key) {
+            Some((value, used)) => {
+                self.order.remove(used);
+                self.order.insert(self.tick, key.clone());
+                *used = self.tick;
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        }
+
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Returns a cached value, or computes and caches it on a miss. The cache
+/// isn't locked while computing, so two requests may compute the same value.
+pub fn get_or_insert<K, V, E>(
+    cache: &Mutex<Lru<K, V>>,
+    key: K,
+    compute: impl FnOnce() -> Result<V, E>,
+) -> Result<V, E>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    if let Some(value) = cache.lock().unwrap().get(&key) {
+        return Ok(value);
+    }
+
+    let value = compute()?;
+    cache.lock().unwrap().insert(key, value.clone());
+
+    Ok(value)
+}
+
+/// An opened repository, returned to the pool for its path when dropped.
+pub struct PooledRepository {
+    path: String,
+    repo: Option<Repository>,
+}
+
+impl PooledRepository {
+    /// Takes an opened handle for `path` from the pool, or opens a new one.
+    pub fn open(path: &str) -> Result<PooledRepository, git2::Error> {
+        let pooled = POOL
+            .lock()
+            .unwrap()
+            .get_mut(path)
+            .and_then(|repos| repos.pop());
+
+        let repo = match pooled {
+            Some(repo) => repo,
+            None => Repository::open(path)?,
+        };
+
+        Ok(PooledRepository {
+            path: String::from(path),
+            repo: Some(repo),
+        })
+    }
+}
+
+impl Deref for PooledRepository {
+    type Target = Repository;
+
+    fn deref(&self) -> &Repository {
+        self.repo.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledRepository {
+    fn deref_mut(&mut self) -> &mut Repository {
+        self.repo.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledRepository {
+    fn drop(&mut self) {
+        if let Some(repo) = self.repo.take() {
+            let mut pool = POOL.lock().unwrap();
+            let repos = pool.entry(self.path.clone()).or_default();
+
+            if repos.len() < POOL_SIZE {
+                repos.push(repo);
+            }
+        }
+    }
+}
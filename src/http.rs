@@ -0,0 +1,271 @@
+use anyhow::Context;
+use futures_core::future::BoxFuture;
+use futures_util::FutureExt;
+use northstar::{Body, Request, Response};
+use std::{io::Cursor, net::SocketAddr, time::Duration};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    actions,
+    listener::{self, Protocol, Received, Stream},
+    repo,
+};
+
+/// Longest request head accepted, the request line and headers together.
+const HEAD_MAX_LEN: usize = 8192;
+
+const HTML_MIME: &str = "text/html; charset=utf-8";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) target: String,
+    pub(crate) host: Option<String>,
+}
+
+/// Serves the pages over plain HTTP on `addr`. Gemtext pages are converted
+/// to HTML, and everything else is sent as it is.
+pub async fn serve(
+    addr: SocketAddr,
+    timeout: Duration,
+    download_timeout: Duration,
+) -> anyhow::Result<()> {
+    listener::serve::<Http>(addr, timeout, download_timeout).await
+}
+
+struct Http;
+
+impl Protocol for Http {
+    const NAME: &'static str = "HTTP";
+
+    /// Whether it's a HEAD request, answered without the body.
+    type Context = bool;
+
+    fn receive(stream: &mut Stream) -> BoxFuture<'_, anyhow::Result<Received<bool>>> {
+        async move {
+            let head = match read_head(stream).await {
+                Some(head) => head,
+                None => {
+                    return send_error(stream, 400, "Bad Request", "")
+                        .await
+                        .map(|_| None)
+                }
+            };
+
+            if head.method != "GET" && head.method != "HEAD" {
+                return send_error(stream, 405, "Method Not Allowed", "")
+                    .await
+                    .map(|_| None);
+            }
+
+            match gemini_request(&head) {
+                Some(request) => Ok(Some((request, head.method == "HEAD"))),
+                None => send_error(stream, 400, "Bad Request", "")
+                    .await
+                    .map(|_| None),
+            }
+        }
+        .boxed()
+    }
+
+    fn send(
+        stream: &mut Stream,
+        response: Response,
+        head_only: bool,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        send_response(stream, response, head_only).boxed()
+    }
+}
+
+/// Reads the request line and headers, keeping the Host header, or returns
+/// `None` if they aren't valid HTTP/1.x.
+pub(crate) async fn read_head(stream: &mut (impl AsyncBufRead + Unpin)) -> Option<RequestHead> {
+    let mut stream = stream.take(HEAD_MAX_LEN as u64);
+    let mut line = String::new();
+
+    stream.read_line(&mut line).await.ok()?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+
+    if !version.starts_with("HTTP/1.") || parts.next().is_some() {
+        return None;
+    }
+
+    let mut head = RequestHead {
+        method: String::from(method),
+        target: String::from(target),
+        host: None,
+    };
+
+    loop {
+        line.clear();
+
+        if stream.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+
+        let header = line.trim_end();
+
+        if header.is_empty() {
+            return Some(head);
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("host") {
+                head.host = Some(String::from(value.trim()));
+            }
+        }
+    }
+}
+
+/// Builds the Gemini request for an HTTP request, leaving out the port from
+/// its Host header.
+pub(crate) fn gemini_request(head: &RequestHead) -> Option<Request> {
+    let host = head.host.as_deref().unwrap_or("localhost");
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+
+    actions::gemini_request(host, &head.target)
+}
+
+/// Returns the HTTP status for a Gemini one.
+pub(crate) fn http_status(code: u8) -> (u16, &'static str) {
+    match code {
+        20..=29 => (200, "OK"),
+        30 => (302, "Found"),
+        31 => (301, "Moved Permanently"),
+        40 | 41 => (503, "Service Unavailable"),
+        43 => (502, "Bad Gateway"),
+        44 => (429, "Too Many Requests"),
+        51 => (404, "Not Found"),
+        52 => (410, "Gone"),
+        10..=19 | 53 | 59 => (400, "Bad Request"),
+        60..=69 => (403, "Forbidden"),
+        _ => (500, "Internal Server Error"),
+    }
+}
+
+/// Sends a response, converting gemtext pages to HTML unless they are larger
+/// than `max_blob_size`, in which case they are sent as they are.
+pub(crate) async fn send_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut response: Response,
+    head_only: bool,
+) -> anyhow::Result<()> {
+    let code = response.header().status.code();
+    let meta = String::from(response.header().meta.as_str());
+    let (status, reason) = http_status(code);
+
+    match code {
+        20..=29 if meta.starts_with("text/gemini") => {
+            let max_size = repo::repo_dir().config.max_blob_size;
+            let gemtext = match response.take_body() {
+                Some(Body::Bytes(bytes)) => bytes,
+                Some(Body::Reader(mut reader)) => {
+                    let mut bytes = vec![];
+                    (&mut reader)
+                        .take(max_size as u64 + 1)
+                        .read_to_end(&mut bytes)
+                        .await?;
+
+                    if bytes.len() > max_size {
+                        let body = Body::Reader(Box::new(Cursor::new(bytes).chain(reader)));
+                        return send(stream, status, reason, &meta, body, head_only).await;
+                    }
+
+                    bytes
+                }
+                None => vec![],
+            };
+
+            if gemtext.len() > max_size {
+                return send(
+                    stream,
+                    status,
+                    reason,
+                    &meta,
+                    Body::from(gemtext),
+                    head_only,
+                )
+                .await;
+            }
+
+            let html = actions::html_page(&String::from_utf8_lossy(&gemtext))?;
+
+            send(
+                stream,
+                status,
+                reason,
+                HTML_MIME,
+                Body::from(html),
+                head_only,
+            )
+            .await
+        }
+        20..=29 => {
+            let body = response.take_body().unwrap_or_else(|| Body::from(vec![]));
+
+            send(stream, status, reason, &meta, body, head_only).await
+        }
+        30 | 31 => {
+            let head = format!(
+                "HTTP/1.1 {} {}\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status, reason, meta
+            );
+
+            stream.write_all(head.as_bytes()).await?;
+            stream.flush().await.context("Failed to send response")
+        }
+        _ => send_error(stream, status, reason, &meta).await,
+    }
+}
+
+/// Sends an error page, with the reason given by the Gemini response if any.
+async fn send_error(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let gemtext = format!("# {}\r\n\r\n{}\r\n", reason, message);
+    let html = actions::html_page(&gemtext)?;
+
+    send(stream, status, reason, HTML_MIME, Body::from(html), false).await
+}
+
+async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: Body,
+    head_only: bool,
+) -> anyhow::Result<()> {
+    let length = match &body {
+        Body::Bytes(bytes) => format!("Content-Length: {}\r\n", bytes.len()),
+        Body::Reader(_) => String::new(),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}Connection: close\r\n\r\n",
+        status, reason, content_type, length
+    );
+
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .context("Failed to send response")?;
+
+    if !head_only {
+        match body {
+            Body::Bytes(bytes) => stream.write_all(&bytes).await?,
+            Body::Reader(mut reader) => {
+                io::copy(&mut reader, stream).await?;
+            }
+        }
+    }
+
+    stream.flush().await.context("Failed to send response")
+}
@@ -3,36 +3,431 @@ extern crate lazy_static;
 
 pub mod util;
 pub mod repo;
+pub mod cache;
+pub mod titan;
+pub mod http;
+pub mod spartan;
+mod listener;
 
 pub mod actions {
-    use northstar::{GEMINI_MIME, Body, Request, Response};
+    use northstar::{routing::RoutingNode, uri::URIReference, GEMINI_MIME, Body, Meta, Request, Response, ResponseHeader, Status};
     use anyhow::anyhow;
-    use futures_core::future::BoxFuture;
+    use chrono::{SecondsFormat, Utc};
+    use futures_core::{future::BoxFuture, Stream};
     use futures_util::FutureExt;
-    use handlebars::Handlebars;
+    use handlebars::{handlebars_helper, Handlebars};
+    use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+    use serde::Serialize;
     use serde_json::json;
     use mime;
+    use log::error;
+    use std::{convert::TryFrom, io::{self, Write}, path::Path, pin::Pin, sync::{Arc, RwLock}, task::{Context, Poll}, time::Duration};
+    use tokio::{io::{AsyncRead, ReadBuf}, sync::{mpsc, Semaphore}, task, time};
 
-    use crate::{repo, repo::ItemType};
+    use crate::{repo, repo::{Access, ArchiveFormat, ItemType, Location}, util};
 
-    async fn get_html(name: &str, template_path: &str, json: &serde_json::Value) -> anyhow::Result<Response> {
+    const TEMPLATES: [(&str, &str); 14] = [
+        ("header", include_str!("../templates/header.hbs")),
+        ("breadcrumbs", include_str!("../templates/breadcrumbs.hbs")),
+        ("footer", include_str!("../templates/footer.hbs")),
+        ("index", include_str!("../templates/index.hbs")),
+        ("ns", include_str!("../templates/ns.hbs")),
+        ("repo", include_str!("../templates/repo.hbs")),
+        ("status", include_str!("../templates/status.hbs")),
+        ("tree", include_str!("../templates/tree.hbs")),
+        ("too_large", include_str!("../templates/too_large.hbs")),
+        ("lfs", include_str!("../templates/lfs.hbs")),
+        ("atom", include_str!("../templates/atom.hbs")),
+        ("log", include_str!("../templates/log.hbs")),
+        ("tags", include_str!("../templates/tags.hbs")),
+        ("html", include_str!("../templates/html.hbs")),
+    ];
+
+    const ATOM_MIME: &str = "application/atom+xml";
+
+    const ROBOTS: &str = include_str!("../static/robots.txt");
+
+    /// Characters escaped in a URL path segment.
+    const SEGMENT: &AsciiSet = &CONTROLS
+        .add(b' ').add(b'"').add(b'#').add(b'%').add(b'/')
+        .add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+    handlebars_helper!(url_helper: |segment: str| encode(segment));
+
+    fn encode(segment: &str) -> String {
+        utf8_percent_encode(segment, SEGMENT).to_string()
+    }
+
+    /// Encodes the branch and directory path following a tree id in links, so
+    /// tree pages know where they were reached from.
+    pub(crate) fn tree_context<'a>(branch: &'a str, dirs: impl IntoIterator<Item = &'a str>) -> String {
+        branch
+            .split('/')
+            .chain(dirs)
+            .map(encode)
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+
+    #[derive(Serialize)]
+    struct Breadcrumb {
+        url: String,
+        icon: &'static str,
+        name: String,
+    }
+
+    /// Builds the breadcrumbs from the index down to a group or repo, then the
+    /// branch and the directories, with their tree ids, within it. The last
+    /// one is the current page.
+    fn breadcrumbs(path: &str, is_repo: bool, branch: Option<&str>, dirs: &[(String, String)]) -> Vec<Breadcrumb> {
+        let mut crumbs = vec![Breadcrumb {
+            url: String::from("/"),
+            icon: "\u{1F3E0}",
+            name: String::from("Index"),
+        }];
+        let mut url = String::new();
+        let segments = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
+
+        for (i, segment) in segments.iter().enumerate() {
+            url = format!("{}/{}", url, segment);
+            crumbs.push(Breadcrumb {
+                url: url.clone(),
+                icon: if is_repo && i == segments.len() - 1 { "\u{1F4DA}" } else { "\u{1F4C1}" },
+                name: String::from(*segment),
+            });
+        }
+
+        if let Some(branch) = branch {
+            crumbs.push(Breadcrumb {
+                url: format!("{}/branch/{}", url, tree_context(branch, None)),
+                icon: "\u{1F38B}",
+                name: String::from(branch),
+            });
+
+            for (depth, (name, id)) in dirs.iter().enumerate() {
+                let context = tree_context(branch, dirs[..=depth].iter().map(|(name, _)| name.as_str()));
+
+                crumbs.push(Breadcrumb {
+                    url: format!("{}/tree/{}/{}", url, id, context),
+                    icon: "\u{1F4C1}",
+                    name: name.clone(),
+                });
+            }
+        }
+
+        crumbs
+    }
+
+    lazy_static! {
+        static ref REGISTRY: RwLock<Arc<Registry>> = RwLock::new(Arc::new(Registry {
+            gemtext: Handlebars::new(),
+            markup: Handlebars::new()
+        }));
+        static ref GIT_TASKS: Arc<Semaphore> = Arc::new(Semaphore::new(repo::repo_dir().config.max_git_tasks));
+        static ref DOWNLOADS: Arc<Semaphore> = Arc::new(Semaphore::new(repo::repo_dir().config.max_downloads));
+    }
+
+    /// Entries in each Atom feed.
+    const FEED_ENTRIES: usize = 20;
+
+    /// Commits shown on a branch's log page.
+    const LOG_ENTRIES: usize = 50;
+
+    const CHUNK_SIZE: usize = 64 * 1024;
+    const CHUNKS_IN_FLIGHT: usize = 4;
+
+    /// Reads the chunks of a download as they arrive from its blocking task.
+    struct ChunkReader {
+        chunks: mpsc::Receiver<Vec<u8>>,
+        chunk: Vec<u8>,
+        pos: usize,
+    }
+
+    impl AsyncRead for ChunkReader {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            while self.pos == self.chunk.len() {
+                match Pin::new(&mut self.chunks).poll_next(cx) {
+                    Poll::Ready(Some(chunk)) => {
+                        self.chunk = chunk;
+                        self.pos = 0;
+                    }
+                    Poll::Ready(None) => return Poll::Ready(Ok(())),
+                    Poll::Pending => return Poll::Pending
+                }
+            }
+
+            let len = buf.remaining().min(self.chunk.len() - self.pos);
+            buf.put_slice(&self.chunk[self.pos..self.pos + len]);
+            self.pos += len;
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Passes what is written to it on to a `ChunkReader` in chunks of
+    /// `CHUNK_SIZE`, failing once the client has gone away.
+    struct ChunkWriter {
+        sender: mpsc::Sender<Vec<u8>>,
+        chunk: Vec<u8>,
+    }
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(CHUNK_SIZE - self.chunk.len());
+            self.chunk.extend_from_slice(&buf[..len]);
+
+            if self.chunk.len() == CHUNK_SIZE {
+                self.flush()?;
+            }
+
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.chunk.is_empty() {
+                return Ok(());
+            }
+
+            let chunk = std::mem::take(&mut self.chunk);
+            self.sender.blocking_send(chunk).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    /// The compiled templates. Gemtext pages are rendered as they are, while
+    /// the Atom feed and the HTML page escape what goes into them.
+    pub struct Registry {
+        pub(crate) gemtext: Handlebars<'static>,
+        pub(crate) markup: Handlebars<'static>,
+    }
+
+    /// Compiles the templates and installs them with `set_templates`.
+    pub fn load_templates(config: &util::Config) -> anyhow::Result<()> {
+        set_templates(compile_templates(config)?);
+
+        Ok(())
+    }
+
+    /// Compiles every template into a registry, taking each one from the
+    /// configured templates dir, or the one built into the binary when it
+    /// isn't overridden there.
+    pub fn compile_templates(config: &util::Config) -> anyhow::Result<Registry> {
         let mut hb = Handlebars::new();
+        hb.set_strict_mode(true);
+        hb.set_dev_mode(config.dev_templates);
+        hb.register_helper("url", Box::new(url_helper));
+
+        for (name, source) in TEMPLATES.iter() {
+            let template_path = format!("{}/{}.hbs", config.templates, name);
 
-        hb.register_template_file(name, template_path).unwrap_or_else(|err| {
-            anyhow!("Template registration error: {}", err);
+            let registered = match !config.templates.is_empty() && Path::new(&template_path).is_file() {
+                true => hb.register_template_file(name, &template_path),
+                false => hb.register_template_string(name, source)
+            };
+
+            registered.map_err(|err| anyhow!("Template registration error: {}", err))?;
+        }
+
+        let mut gemtext = hb.clone();
+        gemtext.register_escape_fn(handlebars::no_escape);
+
+        Ok(Registry {
+            gemtext,
+            markup: hb
+        })
+    }
+
+    /// Replaces the registry used to render pages, for requests starting
+    /// after this returns.
+    pub fn set_templates(registry: Registry) {
+        *REGISTRY.write().unwrap() = Arc::new(registry);
+    }
+
+    #[derive(Serialize)]
+    struct FeedEntry {
+        id: String,
+        title: String,
+        updated: String,
+        author: String,
+        link: String,
+        content: String,
+    }
+
+    /// Renders an Atom feed, which was last updated when its newest entry was.
+    fn get_feed(id: String, title: String, link: String, entries: Vec<FeedEntry>) -> anyhow::Result<Response> {
+        let hb = REGISTRY.read().unwrap().clone();
+        let updated = entries.iter()
+            .map(|entry| entry.updated.clone())
+            .max()
+            .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        let feed = json!({
+            "id": id,
+            "title": title,
+            "link": link,
+            "updated": updated,
+            "entries": entries
         });
 
-        match hb.render(name, json) {
+        match hb.markup.render("atom", &feed) {
+            Ok(contents) => Ok(Response::success(&ATOM_MIME.parse::<mime::Mime>().unwrap(), Body::from(contents))),
+            Err(err) => Err(anyhow!("Template rendering error: {}", err))
+        }
+    }
+
+    fn commit_entry(base: &str, repo_path: &str, title: String, commit: repo::CommitItem) -> FeedEntry {
+        FeedEntry {
+            id: format!("{}/{}/commit/{}", base, repo_path, commit.id),
+            title,
+            updated: commit.time,
+            author: commit.author,
+            link: format!("{}/{}/tree/{}", base, repo_path, commit.tree),
+            content: commit.message,
+        }
+    }
+
+    /// How a page is sent: rendered from its template, or as the JSON the
+    /// template is rendered from, for the API.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Format {
+        Gemtext,
+        Json,
+    }
+
+    /// Renders a page, adding the heading, breadcrumbs and title used by the
+    /// header and footer partials to `json`. For the API `json` is sent as is.
+    fn get_html(format: Format, name: &str, heading: &str, breadcrumbs: Vec<Breadcrumb>, mut json: serde_json::Value) -> anyhow::Result<Response> {
+        if format == Format::Json {
+            return Ok(Response::success(&mime::APPLICATION_JSON, Body::from(json.to_string())));
+        }
+
+        let hb = REGISTRY.read().unwrap().clone();
+
+        if let Some(page) = json.as_object_mut() {
+            page.insert(String::from("title"), json!(repo::repo_dir().config.title));
+            page.insert(String::from("heading"), json!(heading));
+            page.insert(String::from("breadcrumbs"), json!(breadcrumbs));
+        }
+
+        match hb.gemtext.render(name, &json) {
             Ok(contents) => Ok(Response::success(&GEMINI_MIME, Body::from(contents))),
             Err(err) => Err(anyhow!("Template rendering error: {}", err))
         }
     }
 
-    async fn get_blob(path_segments: &Vec<String>) -> anyhow::Result<Response> {
-        let repo_path = format!("{}/{}", path_segments[0], path_segments[1]);
-        let repo = repo::Repo::new(&repo_path).unwrap();
+    /// Converts a gemtext page to HTML, titled with its first heading, for the
+    /// HTTP front-end.
+    pub(crate) fn html_page(gemtext: &str) -> anyhow::Result<String> {
+        let hb = REGISTRY.read().unwrap().clone();
+        let title = gemtext.lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(String::from)
+            .unwrap_or_else(|| format!("{} Gemini Git Browser", repo::repo_dir().config.title));
+
+        let page = json!({
+            "title": title.trim(),
+            "body": util::gemtext_to_html(gemtext)
+        });
+
+        hb.markup.render("html", &page).map_err(|err| anyhow!("Template rendering error: {}", err))
+    }
+
+    /// Guesses the MIME type of a file from its name, for content that isn't
+    /// shown as text.
+    fn guess_mime(name: Option<&String>) -> mime::Mime {
+        name.and_then(|name| mime_guess::from_path(name).first())
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+    }
+
+    /// Renders a page about a blob that isn't shown inline.
+    fn get_blob_info(format: Format, template: &str, repo_path: &str, hash: &str, mut json: serde_json::Value) -> anyhow::Result<Response> {
+        let mut crumbs = breadcrumbs(repo_path, true, None, &[]);
+        crumbs.push(Breadcrumb {
+            url: format!("/{}/blob/{}", repo_path, hash),
+            icon: "\u{1F4C4}",
+            name: String::from(hash),
+        });
+
+        if let Some(page) = json.as_object_mut() {
+            page.insert(String::from("path"), json!(repo_path));
+            page.insert(String::from("hash"), json!(hash));
+        }
+
+        get_html(format, template, &format!("{} - Gemini Git Browser", repo_path), crumbs, json)
+    }
+
+    /// Describes a blob for the API instead of sending its content: its size,
+    /// whether it's too large to be shown, and the LFS file it points to.
+    fn get_blob_json(repo: &repo::Repo, repo_path: &str, hash: &str, name: Option<&String>, size: usize) -> anyhow::Result<Response> {
+        let max_blob_size = repo::repo_dir().config.max_blob_size;
+        let pointer = repo.get_lfs_pointer(hash);
+        let lfs = pointer.as_ref().map(|pointer| json!({
+            "oid": pointer.oid,
+            "size": pointer.size,
+            "stored": repo.get_lfs_object(pointer).is_some()
+        }));
+        let size = pointer.map_or(size, |pointer| pointer.size);
+
+        let json = json!({
+            "path": repo_path,
+            "hash": hash,
+            "name": name,
+            "size": size,
+            "too_large": size > max_blob_size,
+            "lfs": lfs
+        });
+
+        Ok(Response::success(&mime::APPLICATION_JSON, Body::from(json.to_string())))
+    }
+
+    /// Shows a blob, or the file an LFS pointer stands for when it's in the
+    /// repo's LFS store. The file name after the hash, if any, is used for the
+    /// MIME type of LFS files.
+    fn get_blob(repo_path: &str, args: &[String], format: Format) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+        let max_blob_size = repo::repo_dir().config.max_blob_size;
+
+        let hash = match args.first() {
+            Some(hash) => hash,
+            None => return Ok(Response::not_found())
+        };
+        let name = args.get(1);
+
+        let size = match repo.get_blob_size(hash) {
+            Ok(size) => size,
+            Err(_) => return Ok(Response::not_found())
+        };
+
+        if format == Format::Json {
+            return get_blob_json(&repo, repo_path, hash, name, size);
+        }
+
+        if let Some(pointer) = repo.get_lfs_pointer(hash) {
+            let path = match repo.get_lfs_object(&pointer) {
+                Some(path) => path,
+                None => return get_blob_info(format, "lfs", repo_path, hash, json!({
+                    "oid": pointer.oid,
+                    "size": pointer.size
+                }))
+            };
+
+            if pointer.size > max_blob_size {
+                return get_blob_info(format, "too_large", repo_path, hash, json!({
+                    "name": name,
+                    "size": pointer.size
+                }));
+            }
+
+            return Ok(Response::success(&guess_mime(name), std::fs::read(path)?));
+        }
 
-        let blob = repo.get_blob(&path_segments[3]).unwrap();
+        if size > max_blob_size {
+            return get_blob_info(format, "too_large", repo_path, hash, json!({
+                "name": name,
+                "size": size
+            }));
+        }
+
+        let blob = repo.get_blob(hash).unwrap();
 
         let application_octet_stream = "application/octet-stream".parse::<mime::Mime>().unwrap();
         let text_gemini = "text/plain".parse::<mime::Mime>().unwrap();
@@ -45,118 +440,679 @@ pub mod actions {
         Ok(Response::success(&mime, blob.content))
     }
 
-    async fn get_tree(path_segments: &Vec<String>) -> anyhow::Result<Response> {
-        let repo_path = format!("{}/{}", path_segments[0], path_segments[1]);
-        let repo = repo::Repo::new(&repo_path).unwrap();
+    /// Streams a blob of any size, or the file an LFS pointer stands for.
+    fn get_raw(repo_path: &str, args: &[String]) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let hash = match args.first() {
+            Some(hash) => hash.clone(),
+            None => return Ok(Response::not_found())
+        };
+
+        if repo.get_blob_size(&hash).is_err() {
+            return Ok(Response::not_found());
+        }
+
+        let lfs_object = repo.get_lfs_pointer(&hash).and_then(|pointer| repo.get_lfs_object(&pointer));
+        let mime = match lfs_object {
+            Some(_) => guess_mime(args.get(1)),
+            None => mime::APPLICATION_OCTET_STREAM
+        };
+
+        if lfs_object.is_none() && !repo.can_stream_blob(&hash) {
+            return Ok(Response::new(ResponseHeader {
+                status: Status::PERMANENT_FAILURE,
+                meta: Meta::new_lossy("File is too large to send")
+            }));
+        }
+
+        download(&mime, move |sender| {
+            let send = |chunk| sender.blocking_send(chunk).is_ok();
+            let sent = match lfs_object {
+                Some(path) => repo::stream_file(&path, CHUNK_SIZE, send),
+                None => repo.stream_blob(&hash, CHUNK_SIZE, send)
+            };
+
+            if let Err(err) = sent {
+                error!("Error streaming blob {}: {}", hash, err);
+            }
+        })
+    }
+
+    /// Streams an archive of a ref, writing it on its own blocking task.
+    fn get_archive(repo_path: &str, args: &[String]) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let file_name = args.join("/");
+        let (reference, format) = match ArchiveFormat::from_file_name(&file_name) {
+            Some(archive) => archive,
+            None => return Ok(Response::not_found())
+        };
+
+        let mime = format.mime().parse::<mime::Mime>().unwrap();
+
+        let archive = match repo.get_archive(reference, format) {
+            Ok(archive) => archive,
+            Err(_) => return Ok(Response::not_found())
+        };
+
+        download(&mime, move |sender| {
+            let writer = ChunkWriter {
+                sender,
+                chunk: vec![]
+            };
+
+            if let Err(err) = repo.write_archive(&archive, writer) {
+                error!("Error streaming archive {}: {}", file_name, err);
+            }
+        })
+    }
+
+    /// Serves the files git's dumb transport asks for, streaming packs and
+    /// loose objects.
+    fn get_clone(repo_path: &str, args: &[String]) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let file_path = args.iter().map(|s| s.as_str()).collect::<Vec<&str>>();
+
+        let text = match file_path.as_slice() {
+            ["info", "refs"] => Some(repo.get_info_refs()),
+            ["HEAD"] => Some(repo.get_head_ref()),
+            ["objects", "info", "packs"] => Some(repo.get_info_packs()),
+            _ => None
+        };
+
+        if let Some(text) = text {
+            return match text {
+                Ok(text) => Ok(Response::success(&mime::TEXT_PLAIN, Body::from(text))),
+                Err(_) => Ok(Response::not_found())
+            };
+        }
+
+        match file_path.as_slice() {
+            ["objects", "pack", name] => {
+                let path = match repo.get_pack_path(name) {
+                    Ok(path) => path,
+                    Err(_) => return Ok(Response::not_found())
+                };
+
+                download(&mime::APPLICATION_OCTET_STREAM, move |sender| {
+                    let send = |chunk| sender.blocking_send(chunk).is_ok();
 
-        let tree_response = repo.get_tree(&path_segments[3]).unwrap();
+                    if let Err(err) = repo::stream_file(&path, CHUNK_SIZE, send) {
+                        error!("Error streaming pack {:?}: {}", path, err);
+                    }
+                })
+            }
+            ["objects", dir, file] if dir.len() == 2 => {
+                let hash = format!("{}{}", dir, file);
+
+                if !repo.has_object(&hash) {
+                    return Ok(Response::not_found());
+                }
+
+                if !repo.can_stream_object(&hash) {
+                    return Ok(Response::new(ResponseHeader {
+                        status: Status::PERMANENT_FAILURE,
+                        meta: Meta::new_lossy("Object is too large to send")
+                    }));
+                }
+
+                download(&mime::APPLICATION_OCTET_STREAM, move |sender| {
+                    let writer = ChunkWriter {
+                        sender,
+                        chunk: vec![]
+                    };
+
+                    if let Err(err) = repo.write_loose_object(&hash, writer) {
+                        error!("Error streaming object {}: {}", hash, err);
+                    }
+                })
+            }
+            _ => Ok(Response::not_found())
+        }
+    }
+
+    /// Sends what `send` passes to its channel as the body of a download. It
+    /// runs on its own blocking task, with at most `max_downloads` at once,
+    /// and the channel only holds a few chunks, so each download only keeps
+    /// those in memory.
+    fn download<F>(mime: &mime::Mime, send: F) -> anyhow::Result<Response>
+    where
+        F: FnOnce(mpsc::Sender<Vec<u8>>) + Send + 'static
+    {
+        let permit = match DOWNLOADS.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => return Ok(Response::new(ResponseHeader {
+                status: Status::SLOW_DOWN,
+                meta: Meta::new_lossy("5")
+            }))
+        };
+
+        let (sender, chunks) = mpsc::channel(CHUNKS_IN_FLIGHT);
+
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            send(sender);
+        });
+
+        let reader = ChunkReader {
+            chunks,
+            chunk: vec![],
+            pos: 0
+        };
+
+        Ok(Response::success(mime, Body::Reader(Box::new(reader))))
+    }
+
+    fn get_status(repo_path: &str, format: Format) -> anyhow::Result<Response> {
+        if !repo::repo_dir().config.working_tree_status {
+            return Ok(Response::not_found());
+        }
+
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let status = match repo.get_status() {
+            Ok(status) => status,
+            Err(_) => return Ok(Response::not_found())
+        };
+
+        let mut crumbs = breadcrumbs(repo_path, true, None, &[]);
+        crumbs.push(Breadcrumb {
+            url: format!("/{}/status", repo_path),
+            icon: "\u{1F4DD}",
+            name: String::from("status"),
+        });
+
+        get_html(
+            format,
+            "status",
+            &format!("{} - Working tree status", repo_path),
+            crumbs,
+            json!({
+                "path": repo_path,
+                "status": status
+            })
+        )
+    }
+
+    fn get_tree(repo_path: &str, args: &[String], format: Format) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let hash = match args.first() {
+            Some(hash) => hash,
+            None => return Ok(Response::not_found())
+        };
+
+        let tree_response = match repo.get_tree(hash) {
+            Ok(tree_response) => tree_response,
+            Err(_) => return Ok(Response::not_found())
+        };
         let trees = tree_response.get_by_type(ItemType::Tree);
         let blobs = tree_response.get_by_type(ItemType::Blob);
 
+        let (branch, dirs) = match repo.split_branch(&args[1..]) {
+            Some((branch, path)) => match repo.get_path_ids(&branch, &path) {
+                Ok(ids) => (Some(branch), path.into_iter().zip(ids).collect()),
+                Err(_) => (None, vec![])
+            },
+            None => (None, vec![])
+        };
+        let context = match &branch {
+            Some(branch) => tree_context(branch, dirs.iter().map(|(name, _)| name.as_str())),
+            None => String::new()
+        };
+
+        let mut crumbs = breadcrumbs(repo_path, true, branch.as_deref(), &dirs);
+
+        if branch.is_none() {
+            crumbs.push(Breadcrumb {
+                url: format!("/{}/tree/{}", repo_path, hash),
+                icon: "\u{1F4C1}",
+                name: hash.clone(),
+            });
+        }
+
         get_html(
-            "branch",
-            "./templates/tree.hbs",
-            &json!({
+            format,
+            "tree",
+            &format!("{} - Gemini Git Browser", repo_path),
+            crumbs,
+            json!({
                 "path": repo_path,
+                "context": context,
                 "trees": trees,
                 "blobs": blobs,
                 "readme": tree_response.readme_text
             })
-        ).await
+        )
     }
 
-    async fn get_branch(path_segments: &Vec<String>) -> anyhow::Result<Response> {
-        let repo_path = format!("{}/{}", path_segments[0], path_segments[1]);
-        let repo = repo::Repo::new(&repo_path).unwrap();
+    fn get_branch(repo_path: &str, args: &[String], format: Format) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
 
-        let branch = &path_segments[3];
+        let branch = args.join("/");
 
-        let tree_response = repo.get_branch_tree(&branch).unwrap();
+        let tree_response = match repo.get_branch_tree(&branch) {
+            Ok(tree_response) => tree_response,
+            Err(_) => return Ok(Response::not_found())
+        };
         let trees = tree_response.get_by_type(ItemType::Tree);
         let blobs = tree_response.get_by_type(ItemType::Blob);
 
         get_html(
-            "branch",
-            "./templates/tree.hbs",
-            &json!({
+            format,
+            "tree",
+            &format!("{} - {}", repo_path, branch),
+            breadcrumbs(repo_path, true, Some(&branch), &[]),
+            json!({
                 "path": repo_path,
-                "title": branch,
+                "context": tree_context(&branch, None),
                 "trees": trees,
                 "blobs": blobs,
                 "readme": tree_response.readme_text
             })
-        ).await
+        )
+    }
+
+    /// Serves the Atom feed of the latest commits on a branch.
+    fn get_branch_feed(repo_path: &str, args: &[String], base: &str) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let branch = args.join("/");
+
+        let commits = match repo.get_commits(&branch, FEED_ENTRIES) {
+            Ok(commits) => commits,
+            Err(_) => return Ok(Response::not_found())
+        };
+
+        let entries = commits.into_iter()
+            .map(|commit| commit_entry(base, repo_path, commit.summary.clone(), commit))
+            .collect();
+
+        let context = tree_context(&branch, None);
+
+        get_feed(
+            format!("{}/{}/atom/{}", base, repo_path, context),
+            format!("{} - {}", repo_path, branch),
+            format!("{}/{}/branch/{}", base, repo_path, context),
+            entries
+        )
     }
 
-    async fn get_repo(path: &str) -> anyhow::Result<Response> {
-        let repo = repo::Repo::new(path).unwrap();
+    /// Lists the latest commits on a branch as dated links, so Gemini clients
+    /// can subscribe to the page.
+    fn get_log(repo_path: &str, args: &[String], format: Format) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let branch = args.join("/");
+
+        let commits = match repo.get_commits(&branch, LOG_ENTRIES) {
+            Ok(commits) => commits,
+            Err(_) => return Ok(Response::not_found())
+        };
+
+        get_html(
+            format,
+            "log",
+            &format!("{} - {} commits", repo_path, branch),
+            breadcrumbs(repo_path, true, Some(&branch), &[]),
+            json!({
+                "path": repo_path,
+                "context": tree_context(&branch, None),
+                "commits": commits
+            })
+        )
+    }
+
+    /// Lists a repo's tags as dated links at `tags`, and serves them as an
+    /// Atom feed at `tags/atom`.
+    fn get_tags(repo_path: &str, args: &[String], base: &str, format: Format) -> anyhow::Result<Response> {
+        let repo = repo::Repo::new(repo_path).unwrap();
+
+        let tags = match repo.get_tags() {
+            Ok(tags) => tags,
+            Err(e) => return Err(anyhow!("Error listing tags: {}", e))
+        };
+
+        match args {
+            [] => {
+                let mut crumbs = breadcrumbs(repo_path, true, None, &[]);
+                crumbs.push(Breadcrumb {
+                    url: format!("/{}/tags", repo_path),
+                    icon: "\u{1F3F7}\u{FE0F}",
+                    name: String::from("Tags"),
+                });
+
+                return get_html(
+                    format,
+                    "tags",
+                    &format!("{} - Tags", repo_path),
+                    crumbs,
+                    json!({
+                        "path": repo_path,
+                        "tags": tags
+                    })
+                );
+            }
+            [feed] if feed == "atom" => {}
+            _ => return Ok(Response::not_found())
+        }
+
+        let entries = tags.into_iter()
+            .take(FEED_ENTRIES)
+            .map(|tag| FeedEntry {
+                id: format!("{}/{}/tags/{}", base, repo_path, encode(&tag.name)),
+                link: format!("{}/{}/tree/{}", base, repo_path, tag.tree),
+                title: tag.name,
+                updated: tag.time,
+                author: tag.author,
+                content: tag.message,
+            })
+            .collect();
+
+        get_feed(
+            format!("{}/{}/tags/atom", base, repo_path),
+            format!("{} - Tags", repo_path),
+            format!("{}/{}", base, repo_path),
+            entries
+        )
+    }
 
-        let repo_details = repo.get_details().unwrap();
+    /// Serves the Atom feed of the most recently updated repos the client can
+    /// see, with the newest commit of each.
+    fn get_updates_feed(fingerprint: Option<&str>, base: &str) -> anyhow::Result<Response> {
+        let repo_dir = repo::repo_dir();
+        let repos = match repo_dir.get_repos(fingerprint) {
+            Ok(repos) => repos,
+            Err(e) => return Err(anyhow!("Error fetching repo list: {}", e))
+        };
+
+        let mut entries = repos.iter()
+            .filter_map(|path| {
+                let repo = repo::Repo::new(path).ok()?;
+                let (branch, commit) = repo.get_latest_commit().ok()??;
+                let title = format!("{} ({}): {}", path, branch, commit.summary);
+
+                Some(commit_entry(base, path, title, commit))
+            })
+            .collect::<Vec<FeedEntry>>();
+
+        entries.sort_by(|a, b| b.updated.cmp(&a.updated));
+        entries.truncate(FEED_ENTRIES);
+
+        get_feed(
+            format!("{}/atom", base),
+            format!("{} Gemini Git Browser", repo_dir.config.title),
+            format!("{}/", base),
+            entries
+        )
+    }
+
+    fn get_repo(path: &str, format: Format) -> anyhow::Result<Response> {
+        let repo_dir = repo::repo_dir();
+        let repo_details = match repo::Repo::new(path).and_then(|repo| repo.get_details()) {
+            Ok(repo_details) => repo_details,
+            Err(_) => return Ok(Response::not_found())
+        };
         let trees = repo_details.tree_response.get_by_type(ItemType::Tree);
         let blobs = repo_details.tree_response.get_by_type(ItemType::Blob);
+        let (ns, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let clone_urls = repo_dir.config.get_clone_urls(ns, name);
+
+        let context = tree_context(&repo_details.active_branch, None);
+        let branches = repo_details
+            .branches
+            .iter()
+            .map(|branch| json!({"name": branch, "context": tree_context(branch, None)}))
+            .collect::<Vec<serde_json::Value>>();
 
         get_html(
+            format,
             "repo",
-            "./templates/repo.hbs",
-            &json!({
+            &format!("{} - Gemini Git Browser", path),
+            breadcrumbs(path, true, None, &[]),
+            json!({
                 "path": path,
+                "context": context,
                 "details": repo_details,
+                "branches": branches,
+                "show_status": repo_dir.config.working_tree_status,
+                "clone_urls": clone_urls,
                 "trees": trees,
                 "blobs": blobs
             })
-        ).await
+        )
     }
 
-    async fn get_repo_list(path: &str) -> anyhow::Result<Response> {
-        let repos = repo::REPO_DIR.get_ns_repos(path).unwrap();
+    fn get_repo_list(path: &str, fingerprint: Option<&str>, format: Format) -> anyhow::Result<Response> {
+        let repo_dir = repo::repo_dir();
+        let group = match repo_dir.get_group(path, fingerprint) {
+            Ok(group) => group,
+            Err(_) => return Ok(Response::not_found())
+        };
 
-        let repo_list = serde_json::to_value(&repos).unwrap();
-        get_html("ns", "./templates/ns.hbs", &json!({"title": repo::REPO_DIR.config.title, "ns": path, "repos": &repo_list})).await
+        get_html(
+            format,
+            "ns",
+            &format!("{} - Gemini Git Browser", path),
+            breadcrumbs(path, false, None, &[]),
+            json!({
+                "ns": path,
+                "groups": group.groups,
+                "repos": group.repos
+            })
+        )
     }
 
-    async fn get_page(request: Request) -> anyhow::Result<Response> {
-        let segments = request
+    /// Percent decodes the request path into its non-empty segments, which
+    /// are checked against the repo dir by `RepoDir::locate`.
+    pub(crate) fn request_segments(request: &Request) -> Vec<String> {
+        request
             .path_segments()
-            .iter()
-            .filter_map(|s| {
-                if let 0 = s.len() { None } else { Some(String::from(s)) }
-            }
-            ).collect::<Vec<String>>();
-
-        let path = segments.join("/");
-
-        match segments.len() {
-            1 => get_repo_list(&path).await,
-            2 => get_repo(&path).await,
-            _ => {
-                match segments[2].as_str() {
-                    "branch" => get_branch(&segments).await,
-                    "tree" => get_tree(&segments).await,
-                    "blob" => get_blob(&segments).await,
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<String>>()
+    }
+
+    /// Returns the scheme and authority the request was sent to, for the
+    /// absolute links in feeds.
+    fn base_url(request: &Request) -> String {
+        match request.uri().authority() {
+            Some(authority) => format!("gemini://{}", authority),
+            None => String::from("gemini://localhost")
+        }
+    }
+
+    fn get_page(segments: &[String], fingerprint: Option<&str>, base: &str, format: Format) -> anyhow::Result<Response> {
+        match repo::repo_dir().locate(segments, fingerprint) {
+            Ok(Location::Group(path)) => get_repo_list(&path, fingerprint, format),
+            Ok(Location::Repo(path, rest)) => {
+                let args = rest.get(1..).unwrap_or(&[]);
+
+                match rest.first().map(|s| s.as_str()) {
+                    None => get_repo(&path, format),
+                    Some("branch") => get_branch(&path, args, format),
+                    Some("tree") => get_tree(&path, args, format),
+                    Some("blob") => get_blob(&path, args, format),
+                    Some("raw") => get_raw(&path, args),
+                    Some("archive") => get_archive(&path, args),
+                    Some("clone") => get_clone(&path, args),
+                    Some("status") => get_status(&path, format),
+                    Some("log") => get_log(&path, args, format),
+                    Some("atom") => get_branch_feed(&path, args, base),
+                    Some("tags") => get_tags(&path, args, base, format),
                     _ => Ok(Response::not_found())
                 }
             }
+            Err(Access::NotFound) => Ok(Response::not_found()),
+            Err(Access::CertificateRequired) => Ok(Response::client_certificate_required()),
+            Err(Access::NotAuthorized) => Ok(Response::certificate_not_authorized())
         }
     }
 
+    fn get_index(fingerprint: Option<&str>, format: Format) -> anyhow::Result<Response> {
+        let repo_dir = repo::repo_dir();
+        let repos = match repo_dir.get_repos(fingerprint) {
+            Ok(repos) => repos,
+            Err(e) => return Err(anyhow!("Error fetching repo list: {}", e))
+        };
+
+        let repos_json = serde_json::to_value(&repos).unwrap();
+
+        get_html(
+            format,
+            "index",
+            &format!("{} Gemini Git Browser", repo_dir.config.title),
+            vec![],
+            json!({"repos": repos_json})
+        )
+    }
+
+    /// Runs git and filesystem work on the blocking pool, with at most
+    /// `max_git_tasks` running at once.
+    pub(crate) async fn git_task<F>(work: F) -> anyhow::Result<Response>
+    where
+        F: FnOnce() -> anyhow::Result<Response> + Send + 'static
+    {
+        let permit = GIT_TASKS.clone().acquire_owned().await;
+
+        let task = task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        });
+
+        match task.await {
+            Ok(response) => response,
+            Err(err) => Err(anyhow!("Page task failed: {}", err))
+        }
+    }
+
+    /// Runs a page's work with `git_task`. Gives up with a temporary failure
+    /// when the page isn't ready within `timeout`, including the time spent
+    /// waiting for a free task, though the task itself still runs to
+    /// completion.
+    pub(crate) async fn blocking<F>(page: F) -> anyhow::Result<Response>
+    where
+        F: FnOnce() -> anyhow::Result<Response> + Send + 'static
+    {
+        let deadline = Duration::from_secs(repo::repo_dir().config.timeout);
+
+        match time::timeout(deadline, git_task(page)).await {
+            Ok(response) => response,
+            Err(_) => Ok(Response::new(ResponseHeader {
+                status: Status::TEMPORARY_FAILURE,
+                meta: Meta::new_lossy("Timed out, try again later")
+            }))
+        }
+    }
+
+    /// A handler for the requests to a route.
+    pub type Handler = fn(Request) -> BoxFuture<'static, anyhow::Result<Response>>;
+
+    /// The routes served over every protocol. A route also handles the paths
+    /// below it, unless a longer route matches them.
+    pub const ROUTES: [(&str, Handler); 6] = [
+        ("/robots.txt", robots),
+        ("/favicon.txt", favicon),
+        ("/index.gmi", robots),
+        ("/metrics", metrics),
+        ("/atom", atom),
+        ("/", index),
+    ];
+
+    /// Builds the router for the listeners other than Gemini's.
+    pub(crate) fn routes() -> RoutingNode<Handler> {
+        let mut routes = RoutingNode::default();
+
+        for (path, handler) in ROUTES.iter() {
+            routes.add_route(path, *handler);
+        }
+
+        routes
+    }
+
+    /// Builds the request for a page served over another protocol, as if it
+    /// was sent to the Gemini port of `host`, so absolute links in feeds point
+    /// to the capsule. `target` is the path and query, still percent encoded.
+    pub(crate) fn gemini_request(host: &str, target: &str) -> Option<Request> {
+        let valid_host = !host.is_empty()
+            && host.bytes().all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b));
+
+        if !valid_host || !target.starts_with('/') {
+            return None;
+        }
+
+        let port = match repo::repo_dir().config.port {
+            northstar::GEMINI_PORT => String::new(),
+            port => format!(":{}", port)
+        };
+
+        let url = format!("gemini://{}{}{}", host, port, target);
+        let uri = URIReference::try_from(url.as_str()).ok()?.into_owned();
+
+        Request::from_uri(uri).ok()
+    }
+
+    /// Looks up a `key=value` pair in the query, which northstar has already
+    /// percent decoded.
+    fn query_value<'a>(request: &'a Request, key: &str) -> Option<&'a str> {
+        request
+            .input()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Serves the pages, or with `?format=json` the JSON each one is rendered
+    /// from. Files, archives, clones and feeds are sent as they are.
     pub fn index<'a>(request: Request) -> BoxFuture<'a, anyhow::Result<Response>> {
         async move {
-            if let 0 = request.trailing_segments().len() {
-                let repos = match repo::REPO_DIR.get_repos() {
-                    Ok(repos) => repos,
-                    Err(e) => return Err(anyhow!("Error fetching repo list: {}", e))
-                };
+            let segments = request_segments(&request);
+            let fingerprint = request.certificate().map(|cert| util::cert_fingerprint(&cert.0));
+            let base = base_url(&request);
+            let format = match query_value(&request, "format") {
+                Some("json") => Format::Json,
+                _ => Format::Gemtext
+            };
 
-                let repos_json = serde_json::to_value(&repos).unwrap();
+            blocking(move || match segments.is_empty() {
+                true => get_index(fingerprint.as_deref(), format),
+                false => get_page(&segments, fingerprint.as_deref(), &base, format)
+            }).await
+        }
+        .boxed()
+    }
 
-                get_html("index", "./templates/index.hbs", &json!({"title": repo::REPO_DIR.config.title, "repos": repos_json})).await
-            } else {
-                get_page(request).await
+    /// Serves the cache statistics as plain text when `metrics` is enabled,
+    /// and otherwise handles the path like any other page.
+    pub fn metrics<'a>(request: Request) -> BoxFuture<'a, anyhow::Result<Response>> {
+        async move {
+            match repo::repo_dir().config.metrics {
+                true => Ok(Response::success(&mime::TEXT_PLAIN, Body::from(repo::cache_stats()))),
+                false => index(request).await
             }
         }
         .boxed()
     }
 
+    /// Serves the feed of recently updated repos at `/atom`, and otherwise
+    /// handles the path like any other page.
+    pub fn atom<'a>(request: Request) -> BoxFuture<'a, anyhow::Result<Response>> {
+        async move {
+            if request_segments(&request) != ["atom"] {
+                return index(request).await;
+            }
+
+            let fingerprint = request.certificate().map(|cert| util::cert_fingerprint(&cert.0));
+            let base = base_url(&request);
+
+            blocking(move || get_updates_feed(fingerprint.as_deref(), &base)).await
+        }
+        .boxed()
+    }
+
     pub fn favicon<'a>(_: Request) -> BoxFuture<'a, anyhow::Result<Response>> {
         async move {
             Ok(Response::success(&GEMINI_MIME, Body::from("\u{1F4DA}\r\n")))
@@ -166,9 +1122,11 @@ pub mod actions {
 
     pub fn robots<'a>(_: Request) -> BoxFuture<'a, anyhow::Result<Response>> {
         async move {
-            let response = northstar::util::serve_file("./static/robots.txt", &mime::TEXT_PLAIN).await?;
+            if Path::new("./static/robots.txt").is_file() {
+                return northstar::util::serve_file("./static/robots.txt", &mime::TEXT_PLAIN).await;
+            }
 
-            Ok(response)
+            Ok(Response::success(&mime::TEXT_PLAIN, Body::from(ROBOTS)))
         }
         .boxed()
     }
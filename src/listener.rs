@@ -0,0 +1,98 @@
+use anyhow::Context;
+use futures_core::future::BoxFuture;
+use log::error;
+use northstar::{routing::RoutingNode, Meta, Request, Response, ResponseHeader, Status};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::BufStream,
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::actions::{self, Handler};
+
+pub(crate) type Stream = BufStream<TcpStream>;
+
+/// A request turned into a Gemini one, with the context for its response,
+/// or `None` when it was refused.
+pub(crate) type Received<C> = Option<(Request, C)>;
+
+/// A plain TCP protocol served through the Gemini routes: how its requests
+/// are turned into Gemini ones, and the Gemini responses sent back in its
+/// terms.
+pub(crate) trait Protocol: 'static {
+    /// Name the errors are logged under.
+    const NAME: &'static str;
+
+    /// What `send` needs to know about the request.
+    type Context: Send;
+
+    /// Reads a request, or answers it with the protocol's error and returns
+    /// `None` if it can't be served.
+    fn receive(stream: &mut Stream) -> BoxFuture<'_, anyhow::Result<Received<Self::Context>>>;
+
+    fn send(
+        stream: &mut Stream,
+        response: Response,
+        context: Self::Context,
+    ) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+/// Serves `P` on `addr`. Clients get `timeout` to send their request, and
+/// `download_timeout` to receive the response.
+pub(crate) async fn serve<P: Protocol>(
+    addr: SocketAddr,
+    timeout: Duration,
+    download_timeout: Duration,
+) -> anyhow::Result<()> {
+    let routes = Arc::new(actions::routes());
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+
+    loop {
+        let (stream, _) = listener.accept().await.context("Failed to accept client")?;
+        let routes = routes.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_client::<P>(stream, routes, timeout, download_timeout).await {
+                error!("{}: {:?}", P::NAME, err);
+            }
+        });
+    }
+}
+
+async fn serve_client<P: Protocol>(
+    stream: TcpStream,
+    routes: Arc<RoutingNode<Handler>>,
+    timeout: Duration,
+    download_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut stream = BufStream::new(stream);
+
+    let (request, context) = match time::timeout(timeout, P::receive(&mut stream)).await {
+        Ok(received) => match received? {
+            Some(request) => request,
+            None => return Ok(()),
+        },
+        Err(_) => return Ok(()),
+    };
+
+    let response = match routes.match_request(&request) {
+        Some((_, handler)) => handler(request).await,
+        None => Ok(Response::not_found()),
+    };
+
+    // Both protocols send this as a server error.
+    let response = response.unwrap_or_else(|err| {
+        error!("{}: {:?}", P::NAME, err);
+        Response::new(ResponseHeader {
+            status: Status::CGI_ERROR,
+            meta: Meta::new_lossy("Unexpected error"),
+        })
+    });
+
+    time::timeout(download_timeout, P::send(&mut stream, response, context))
+        .await
+        .context("Client timed out receiving the response")?
+}
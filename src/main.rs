@@ -1,25 +1,281 @@
-use std::{env, time::Duration};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    process,
+    time::{Duration, SystemTime},
+};
 
-use northstar::{Server, GEMINI_PORT};
-use tokio;
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use futures_util::{future::try_join_all, FutureExt};
+use log::{error, info, warn};
+use northstar::Server;
 
-use gemini_git_browser::actions;
+use gemini_git_browser::{actions, http, repo, spartan, titan, util};
+
+/// Browse git repositories over the gemini protocol
+#[derive(Parser, Clone)]
+#[command(version)]
+struct Cli {
+    /// Config file to use instead of $XDG_CONFIG_HOME/gemini-git-browser/gemini-git-browser.toml
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Directory containing the namespace dirs
+    #[arg(long)]
+    repo_dir: Option<String>,
+
+    /// Port used for bind addresses without a port
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on, may be given more than once
+    #[arg(long)]
+    bind: Vec<String>,
+
+    /// TLS certificate
+    #[arg(long)]
+    cert: Option<String>,
+
+    /// TLS private key
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Directory with templates overriding the built-in ones
+    #[arg(long)]
+    templates: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Check the configuration and every allowed entry, then exit
+    CheckConfig,
+}
+
+impl Cli {
+    fn apply(&self, config: &mut util::Config) {
+        if let Some(repo_dir) = &self.repo_dir {
+            config.repo_dir = repo_dir.clone();
+        }
+
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+
+        if !self.bind.is_empty() {
+            config.bind = self.bind.clone();
+        }
+
+        if let Some(cert) = &self.cert {
+            config.cert = cert.clone();
+        }
+
+        if let Some(key) = &self.key {
+            config.key = key.clone();
+        }
+
+        if let Some(templates) = &self.templates {
+            config.templates = templates.clone();
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<(), anyhow::Error> {
     env_logger::init();
 
-    let gemini_port = match env::var("GEMINI_PORT") {
-        Ok(port) => port.parse::<u16>().unwrap(),
-        Err(_) => GEMINI_PORT
+    let cli = Cli::parse();
+
+    let mut config = util::Config::read(cli.config.as_deref()).map_err(|err| anyhow!("{}", err))?;
+    cli.apply(&mut config);
+
+    if let Some(Command::CheckConfig) = cli.command {
+        check_config(config);
+    }
+
+    config.validate().map_err(|err| anyhow!("{}", err))?;
+    actions::load_templates(&config)?;
+
+    let listen_addrs = config.listen_addrs().map_err(|err| anyhow!("{}", err))?;
+    let titan_addrs = match config.titan {
+        true => config.titan_addrs().map_err(|err| anyhow!("{}", err))?,
+        false => vec![],
+    };
+    let http_addrs = match config.http {
+        true => config.http_addrs().map_err(|err| anyhow!("{}", err))?,
+        false => vec![],
+    };
+    let spartan_addrs = match config.spartan {
+        true => config.spartan_addrs().map_err(|err| anyhow!("{}", err))?,
+        false => vec![],
     };
+    let (cert, key) = (config.cert.clone(), config.key.clone());
+    let timeout = Duration::from_secs(config.timeout);
+    let download_timeout = Duration::from_secs(config.download_timeout);
+
+    repo::init(config).map_err(|err| anyhow!("{}", err))?;
+
+    for problem in repo::repo_dir().check_allowed() {
+        warn!("{}", problem);
+    }
+
+    tokio::spawn(watch_config(cli));
+
+    let servers = listen_addrs
+        .into_iter()
+        .map(|addr| serve(addr, cert.clone(), key.clone(), timeout, download_timeout).boxed())
+        .chain(
+            titan_addrs
+                .into_iter()
+                .map(|addr| titan::serve(addr, cert.clone(), key.clone(), timeout).boxed()),
+        )
+        .chain(
+            http_addrs
+                .into_iter()
+                .map(|addr| http::serve(addr, timeout, download_timeout).boxed()),
+        )
+        .chain(
+            spartan_addrs
+                .into_iter()
+                .map(|addr| spartan::serve(addr, timeout, download_timeout).boxed()),
+        );
+
+    try_join_all(servers).await?;
+
+    Ok(())
+}
+
+fn check_config(config: util::Config) -> ! {
+    let mut problems = vec![];
+
+    if let Err(err) = config.validate() {
+        problems.push(err.to_string());
+    }
+
+    if let Err(err) = actions::load_templates(&config) {
+        problems.push(err.to_string());
+    }
+
+    problems.extend(repo::RepoDir::with_config(config).check_allowed());
+
+    if problems.is_empty() {
+        println!("Configuration OK");
+        process::exit(0);
+    }
+
+    for problem in problems {
+        eprintln!("{}", problem);
+    }
+    process::exit(1);
+}
+
+/// Polls the config file, and the repo dir when `watch_repo_dir` is set, every
+/// `reload_interval` seconds and swaps in the new config when it changed and
+/// is valid. Command-line and environment overrides are applied again.
+async fn watch_config(cli: Cli) {
+    let path = match util::Config::path(cli.config.as_deref()) {
+        Ok(path) => path,
+        Err(err) => return error!("Not watching the config file: {}", err),
+    };
+    let mut modified = modified_time(&path);
+
+    loop {
+        let current = repo::repo_dir();
+
+        if current.config.reload_interval == 0 {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_secs(current.config.reload_interval)).await;
+
+        let config_changed = modified_time(&path) != modified;
+
+        if !config_changed && !current.config.watch_repo_dir {
+            continue;
+        }
+
+        modified = modified_time(&path);
+
+        let config = match util::Config::read(cli.config.as_deref()) {
+            Ok(mut config) => {
+                cli.apply(&mut config);
+                config
+            }
+            Err(err) => {
+                error!("Not reloading the configuration: {}", err);
+                continue;
+            }
+        };
+
+        if let Err(err) = config.validate() {
+            error!("Not reloading the configuration: {}", err);
+            continue;
+        }
+
+        let templates = match actions::compile_templates(&config) {
+            Ok(templates) => templates,
+            Err(err) => {
+                error!("Not reloading the configuration: {}", err);
+                continue;
+            }
+        };
+
+        let repo_dir = repo::RepoDir::with_config(config);
+        let mut changes = current.config.changes(&repo_dir.config);
+
+        for repo in &repo_dir.config.discovered {
+            if !current.config.discovered.contains(repo) {
+                changes.push(format!("discovered `{}`", repo));
+            }
+        }
+
+        for repo in &current.config.discovered {
+            if !repo_dir.config.discovered.contains(repo) {
+                changes.push(format!("`{}` is no longer exported", repo));
+            }
+        }
+
+        if changes.is_empty() {
+            continue;
+        }
+
+        for change in changes {
+            info!("Configuration reloaded: {}", change);
+        }
+
+        for problem in repo_dir.check_allowed() {
+            warn!("{}", problem);
+        }
+
+        actions::set_templates(templates);
+        repo::reload(repo_dir);
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+async fn serve(
+    addr: SocketAddr,
+    cert: String,
+    key: String,
+    timeout: Duration,
+    download_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut server = Server::bind(addr).set_cert(cert).set_key(key);
+
+    for (path, handler) in actions::ROUTES.iter() {
+        server = server.add_route(path, *handler);
+    }
 
-    Server::bind(("0.0.0.0", gemini_port))
-        .add_route("/robots.txt", actions::robots)
-        .add_route("/favicon.txt", actions::favicon)
-        .add_route("/index.gmi", actions::robots)
-        .add_route("/", actions::index)
-        .set_timeout(Duration::from_secs(10))
+    server
+        .set_timeout(timeout)
+        .override_complex_body_timeout(Some(download_timeout))
         .serve()
         .await
 }
@@ -1,17 +1,41 @@
-use git2::{ObjectType, Oid, Reference, Repository, TreeEntry};
+use chrono::{DateTime, Datelike, SecondsFormat, Timelike, Utc};
+use flate2::{
+    bufread::ZlibDecoder,
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use git2::{
+    BranchType, Commit, FileMode, ObjectType, Odb, Oid, Reference, Repository, Signature, Sort,
+    Status, StatusOptions, Time, Tree, TreeEntry, TreeWalkMode, TreeWalkResult,
+};
 use serde::Serialize;
-use std::{collections::HashMap, env, error::Error, str};
+use std::{
+    cmp::Ordering,
+    env,
+    error::Error,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process, str,
+    sync::{
+        atomic::{self, AtomicUsize},
+        Arc, Mutex, RwLock,
+    },
+};
 
-use crate::util;
+use crate::{
+    cache::{self, Lru, PooledRepository},
+    util,
+};
 
-#[derive(Serialize, Debug, PartialEq)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ItemType {
     Tree,
     Blob,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct TreeItem {
     id: String,
     name: String,
@@ -20,7 +44,7 @@ pub struct TreeItem {
     content_string: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct TreeResponse {
     pub tree: Vec<TreeItem>,
     pub readme_text: Option<String>,
@@ -31,55 +55,383 @@ pub struct Blob {
     pub content: Vec<u8>,
 }
 
+/// Pointer files are at most this many bytes, so larger blobs aren't read to
+/// check for one.
+const LFS_POINTER_MAX_SIZE: usize = 1024;
+
+/// A Git LFS pointer, standing in for a file stored outside of the repository.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct LfsPointer {
+    pub oid: String,
+    pub size: usize,
+}
+
+impl LfsPointer {
+    /// Parses the content of a blob, returning `None` if it isn't a pointer.
+    pub fn parse(content: &[u8]) -> Option<LfsPointer> {
+        let text = str::from_utf8(content).ok()?;
+        let mut lines = text.lines();
+
+        if lines.next()? != "version https://git-lfs.github.com/spec/v1" {
+            return None;
+        }
+
+        let (mut oid, mut size) = (None, None);
+
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+
+        let oid = oid.filter(|oid| {
+            oid.len() == 64
+                && oid
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        })?;
+
+        Some(LfsPointer {
+            oid: String::from(oid),
+            size: size?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+/// The entries of an archive, listed by `Repo::get_archive`.
+pub struct Archive {
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+    mtime: i64,
+}
+
+struct ArchiveEntry {
+    path: String,
+    id: Oid,
+    mode: i32,
+    kind: ObjectType,
+}
+
 #[derive(Serialize, Debug)]
 pub struct RepoDetails {
-    active_branch: String,
-    branches: Vec<String>,
+    pub active_branch: String,
+    pub branches: Vec<String>,
+    is_bare: bool,
     pub tree_response: TreeResponse,
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub struct CommitItem {
+    pub id: String,
+    pub tree: String,
+    pub summary: String,
+    pub message: String,
+    pub author: String,
+    pub time: String,
+    pub date: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TagItem {
+    pub name: String,
+    pub tree: String,
+    pub message: String,
+    pub author: String,
+    pub time: String,
+    pub date: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusItem {
+    path: String,
+    state: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Group {
+    pub groups: Vec<String>,
+    pub repos: Vec<String>,
+}
+
+pub enum Location {
+    Group(String),
+    Repo(String, Vec<String>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    NotFound,
+    CertificateRequired,
+    NotAuthorized,
+}
+
 pub struct RepoDir {
     dir_path: String,
+    root: Option<PathBuf>,
     pub config: util::Config,
 }
 
-pub struct Repo<'a> {
-    _repo_dir: &'a RepoDir,
+pub struct Repo {
+    repo_dir: Arc<RepoDir>,
     dir_path: String,
-    repo: Repository,
+    repo: PooledRepository,
     pub name: String,
 }
 
+type OidCache<V> = Mutex<Lru<(String, Oid), V>>;
+type BlobReader<'o> = (Box<dyn Read + 'o>, usize);
+type ObjectReader<'o> = (Box<dyn Read + 'o>, usize, ObjectType);
+
 lazy_static! {
-    pub static ref REPO_DIR: RepoDir = RepoDir::new();
+    static ref REPO_DIR: RwLock<Option<Arc<RepoDir>>> = RwLock::new(None);
+    static ref TREES: OidCache<TreeResponse> = Mutex::new(Lru::new(repo_dir().config.cache_size));
+    static ref READMES: OidCache<Option<String>> =
+        Mutex::new(Lru::new(repo_dir().config.cache_size));
+}
+
+/// Sets up `REPO_DIR` with an already loaded and validated config. It can
+/// only be set up once, later configs have to go through `reload`.
+pub fn init(config: util::Config) -> Result<(), Box<dyn Error>> {
+    let mut repo_dir = REPO_DIR.write().unwrap();
+
+    if repo_dir.is_some() {
+        return Err("The repo dir is already set up".into());
+    }
+
+    *repo_dir = Some(Arc::new(RepoDir::with_config(config)));
+
+    Ok(())
+}
+
+/// Returns the current repo dir. Requests should use a single snapshot so a
+/// reload in the middle of one doesn't mix two configs.
+pub fn repo_dir() -> Arc<RepoDir> {
+    REPO_DIR
+        .read()
+        .unwrap()
+        .clone()
+        .expect("repo::init has to be called before the repo dir is used")
+}
+
+/// Replaces the current repo dir, for requests starting after this returns.
+pub fn reload(repo_dir: RepoDir) {
+    *REPO_DIR.write().unwrap() = Some(Arc::new(repo_dir));
+}
+
+/// Passes the content of a file to `send` in chunks, like `Repo::stream_blob`.
+pub fn stream_file(
+    path: &Path,
+    chunk_size: usize,
+    mut send: impl FnMut(Vec<u8>) -> bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+
+    loop {
+        let mut chunk = vec![0; chunk_size];
+        let read = file.read(&mut chunk)?;
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        chunk.truncate(read);
+
+        if !send(chunk) {
+            return Ok(());
+        }
+    }
+}
+
+/// Looks up the offset of an object in a version 2 pack index, the only
+/// version git has written for years.
+fn pack_index_offset(path: &Path, oid: Oid) -> Result<Option<u64>, Box<dyn Error>> {
+    let mut idx = io::BufReader::new(fs::File::open(path)?);
+    let mut header = [0; 8];
+    idx.read_exact(&mut header)?;
+
+    if header != [0xff, b't', b'O', b'c', 0, 0, 0, 2] {
+        return Ok(None);
+    }
+
+    // The fanout table counts the objects whose first byte is at most its
+    // index, and the sorted object ids follow it.
+    let mut fanout = [0; 256 * 4];
+    idx.read_exact(&mut fanout)?;
+
+    let count = |byte: usize| {
+        u64::from(u32::from_be_bytes([
+            fanout[byte * 4],
+            fanout[byte * 4 + 1],
+            fanout[byte * 4 + 2],
+            fanout[byte * 4 + 3],
+        ]))
+    };
+
+    let id = oid.as_bytes();
+    let total = count(255);
+    let ids = 8 + 256 * 4;
+    let offsets = ids + total * 24;
+    let (mut low, mut high) = match id[0] {
+        0 => (0, count(0)),
+        first => (count(usize::from(first) - 1), count(usize::from(first))),
+    };
+
+    while low < high {
+        let middle = (low + high) / 2;
+        let mut name = [0; 20];
+        idx.seek(SeekFrom::Start(ids + middle * 20))?;
+        idx.read_exact(&mut name)?;
+
+        match name.as_ref().cmp(id) {
+            Ordering::Less => low = middle + 1,
+            Ordering::Greater => high = middle,
+            Ordering::Equal => {
+                let mut offset = [0; 4];
+                idx.seek(SeekFrom::Start(offsets + middle * 4))?;
+                idx.read_exact(&mut offset)?;
+                let offset = u32::from_be_bytes(offset);
+
+                if offset & 0x8000_0000 == 0 {
+                    return Ok(Some(u64::from(offset)));
+                }
+
+                // Offsets past 2 GiB are kept in a table of 8-byte ones.
+                let mut large = [0; 8];
+                let index = u64::from(offset & 0x7fff_ffff);
+                idx.seek(SeekFrom::Start(offsets + total * 4 + index * 8))?;
+                idx.read_exact(&mut large)?;
+
+                return Ok(Some(u64::from_be_bytes(large)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the type and inflated size at the start of a pack entry.
+/// Returns the type of a pack entry holding a whole object, or `None` for
+/// the deltas.
+fn pack_object_type(kind: u8) -> Option<ObjectType> {
+    match kind {
+        1 => Some(ObjectType::Commit),
+        2 => Some(ObjectType::Tree),
+        3 => Some(ObjectType::Blob),
+        4 => Some(ObjectType::Tag),
+        _ => None,
+    }
+}
+
+fn read_pack_entry_header(pack: &mut impl Read) -> io::Result<(u8, usize)> {
+    let mut byte = [0; 1];
+    pack.read_exact(&mut byte)?;
+
+    let kind = (byte[0] >> 4) & 0b111;
+    let mut size = usize::from(byte[0] & 0x0f);
+    let mut shift = 4;
+
+    while byte[0] & 0x80 != 0 {
+        if shift > 57 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+
+        pack.read_exact(&mut byte)?;
+        size |= usize::from(byte[0] & 0x7f) << shift;
+        shift += 7;
+    }
+
+    Ok((kind, size))
+}
+
+/// Opens a new file in the temp dir and unlinks it, so it goes away with the
+/// handle.
+fn temp_file() -> io::Result<fs::File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let path = env::temp_dir().join(format!(
+        "gemini-git-browser-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, atomic::Ordering::Relaxed)
+    ));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+
+    Ok(file)
+}
+
+/// Describes the size and hit counts of the tree and README caches.
+pub fn cache_stats() -> String {
+    let trees = TREES.lock().unwrap();
+    let readmes = READMES.lock().unwrap();
+
+    format!(
+        "tree_cache_entries {}\ntree_cache_hits {}\ntree_cache_misses {}\n\
+         readme_cache_entries {}\nreadme_cache_hits {}\nreadme_cache_misses {}\n",
+        trees.len(),
+        trees.hits,
+        trees.misses,
+        readmes.len(),
+        readmes.hits,
+        readmes.misses
+    )
 }
 
 impl TreeResponse {
     pub fn get_by_type(&self, item_type: ItemType) -> Vec<&TreeItem> {
         self.tree
             .iter()
-            .filter_map(|i| {
-                if i.item_type == item_type {
-                    Some(i)
-                } else {
-                    None
-                }
-            })
+            .filter(|i| i.item_type == item_type)
             .collect::<Vec<&TreeItem>>()
     }
 }
 
-impl<'a> Repo<'a> {
+impl ArchiveFormat {
+    pub fn from_file_name(file_name: &str) -> Option<(&str, ArchiveFormat)> {
+        if let Some(reference) = file_name.strip_suffix(".tar.gz") {
+            Some((reference, ArchiveFormat::TarGz))
+        } else {
+            file_name
+                .strip_suffix(".zip")
+                .map(|reference| (reference, ArchiveFormat::Zip))
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+impl Repo {
     pub fn new(repo_path: &str) -> Result<Repo, Box<dyn Error>> {
-        let dir_path = REPO_DIR.get_repo_dir(repo_path)?;
-        let repo = Repository::open(&dir_path)?;
-        let segments: Vec<&str> = repo_path.split("/").collect();
-        let name = segments[1].trim_end_matches(".git");
+        let repo_dir = repo_dir();
+        let dir_path = repo_dir.get_repo_dir(repo_path)?;
+        let repo = PooledRepository::open(&dir_path)?;
+        let name = repo_path
+            .rsplit('/')
+            .next()
+            .unwrap_or(repo_path)
+            .trim_end_matches(".git");
 
         Ok(Repo {
-            dir_path: dir_path,
-            _repo_dir: &REPO_DIR,
-            repo: repo,
+            repo_dir,
+            dir_path,
+            repo,
             name: String::from(name),
         })
     }
@@ -97,39 +449,223 @@ impl<'a> Repo<'a> {
         })
     }
 
+    /// Returns the size of a blob without reading its content.
+    pub fn get_blob_size(&self, hash: &str) -> Result<usize, Box<dyn Error>> {
+        let oid = Oid::from_str(hash)?;
+
+        match self.repo.odb()?.read_header(oid)? {
+            (size, ObjectType::Blob) => Ok(size),
+            _ => Err(format!("No such blob: {}", hash).into()),
+        }
+    }
+
+    /// Returns the LFS pointer stored in a blob, if it is one.
+    pub fn get_lfs_pointer(&self, hash: &str) -> Option<LfsPointer> {
+        if self.get_blob_size(hash).ok()? > LFS_POINTER_MAX_SIZE {
+            return None;
+        }
+
+        let blob = self.repo.find_blob(Oid::from_str(hash).ok()?).ok()?;
+
+        LfsPointer::parse(blob.content())
+    }
+
+    /// Returns the path of an LFS object in the repo's local `lfs/objects`
+    /// store, if it's there and complete.
+    pub fn get_lfs_object(&self, pointer: &LfsPointer) -> Option<PathBuf> {
+        let path = self
+            .repo
+            .path()
+            .join("lfs/objects")
+            .join(&pointer.oid[..2])
+            .join(&pointer.oid[2..4])
+            .join(&pointer.oid);
+
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == pointer.size as u64 => {
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Passes the content of a blob to `send` in chunks of at most
+    /// `chunk_size` bytes, stopping early when it returns false. Loose objects
+    /// are streamed from the object database, and packed ones from their pack
+    /// file when they are stored whole. Deltified ones have to be read whole,
+    /// so they are refused above `max_blob_size`.
+    pub fn stream_blob(
+        &self,
+        hash: &str,
+        chunk_size: usize,
+        mut send: impl FnMut(Vec<u8>) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let oid = Oid::from_str(hash)?;
+        let odb = self.repo.odb()?;
+
+        let (mut reader, mut remaining) = match self.blob_reader(&odb, oid)? {
+            Some(reader) => reader,
+            None => {
+                if self.get_blob_size(hash)? > self.repo_dir.config.max_blob_size {
+                    return Err(format!("Blob {} is too large to read whole", hash).into());
+                }
+
+                let blob = self.repo.find_blob(oid)?;
+
+                for chunk in blob.content().chunks(chunk_size) {
+                    if !send(chunk.to_vec()) {
+                        break;
+                    }
+                }
+
+                return Ok(());
+            }
+        };
+
+        // libgit2 keeps returning data past the end of a loose object, so stop
+        // after the size it reported.
+        while remaining > 0 {
+            let mut chunk = vec![0; chunk_size.min(remaining)];
+            let read = reader.read(&mut chunk)?;
+
+            if read == 0 {
+                break;
+            }
+
+            chunk.truncate(read);
+            remaining -= read;
+
+            if !send(chunk) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `stream_blob` can send a blob without reading more than
+    /// `max_blob_size` bytes of it into memory.
+    pub fn can_stream_blob(&self, hash: &str) -> bool {
+        self.get_blob_size(hash).is_ok() && self.can_stream_object(hash)
+    }
+
+    /// Checks that `write_loose_object` can send an object without reading
+    /// more than `max_blob_size` bytes of it into memory.
+    pub fn can_stream_object(&self, hash: &str) -> bool {
+        let (oid, odb) = match (Oid::from_str(hash), self.repo.odb()) {
+            (Ok(oid), Ok(odb)) => (oid, odb),
+            _ => return false,
+        };
+
+        let streamed = self.object_reader(&odb, oid).map(|reader| reader.is_some());
+
+        match streamed {
+            Ok(true) => true,
+            Ok(false) => odb
+                .read_header(oid)
+                .is_ok_and(|(size, _)| size <= self.repo_dir.config.max_blob_size),
+            Err(_) => false,
+        }
+    }
+
+    /// Opens a reader for the content of a loose blob, or of one stored whole
+    /// in a pack, along with its size. Returns `None` for other blobs, which
+    /// can only be read whole.
+    fn blob_reader<'o>(
+        &self,
+        odb: &'o Odb,
+        oid: Oid,
+    ) -> Result<Option<BlobReader<'o>>, Box<dyn Error>> {
+        match self.object_reader(odb, oid)? {
+            Some((reader, size, ObjectType::Blob)) => Ok(Some((reader, size))),
+            Some(_) => Err(format!("No such blob: {}", oid).into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens a reader for the content of a loose object, or of one stored
+    /// whole in a pack, along with its size and type. Returns `None` for
+    /// deltified objects, which can only be read whole.
+    fn object_reader<'o>(
+        &self,
+        odb: &'o Odb,
+        oid: Oid,
+    ) -> Result<Option<ObjectReader<'o>>, Box<dyn Error>> {
+        if let Ok((reader, size, kind)) = odb.reader(oid) {
+            return Ok(Some((Box::new(reader), size, kind)));
+        }
+
+        let pack_dir = match fs::read_dir(self.repo.path().join("objects/pack")) {
+            Ok(pack_dir) => pack_dir,
+            Err(_) => return Ok(None),
+        };
+
+        for entry in pack_dir {
+            let path = entry?.path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some("idx") {
+                continue;
+            }
+
+            let offset = match pack_index_offset(&path, oid)? {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            let mut pack = io::BufReader::new(fs::File::open(path.with_extension("pack"))?);
+            pack.seek(SeekFrom::Start(offset))?;
+
+            let (kind, size) = read_pack_entry_header(&mut pack)?;
+
+            return match pack_object_type(kind) {
+                Some(kind) => Ok(Some((Box::new(ZlibDecoder::new(pack)), size, kind))),
+                None => Ok(None),
+            };
+        }
+
+        Ok(None)
+    }
+
     fn filter_tree(repo: &Repo, item: TreeEntry) -> Option<TreeItem> {
         let name = String::from(item.name().unwrap());
         let oid_str = item.id().to_string();
 
         let mut contents = None;
 
-        if name == "README.md" {
-            if let Ok(blob) = repo.get_blob(&oid_str) {
-                contents = match str::from_utf8(&blob.content) {
-                    Ok(s) => match util::md_to_gemtext(&s) {
-                        Ok(v) => {
-                            let readme_text =
-                                format!("\r\n\r\n\r\n# \u{1F4D6} README\r\n\r\n\r\n{}", v);
-                            Some(readme_text)
-                        }
+        let max_size = repo.repo_dir.config.max_blob_size;
+        let fits = |oid: &str| repo.get_blob_size(oid).is_ok_and(|size| size <= max_size);
+
+        if name == "README.md" && fits(&oid_str) {
+            let key = (repo.dir_path.clone(), item.id());
+
+            contents = cache::get_or_insert(&READMES, key, || {
+                repo.get_blob(&oid_str)
+                    .map(|blob| match str::from_utf8(&blob.content) {
+                        Ok(s) => match util::md_to_gemtext(s) {
+                            Ok(v) => {
+                                let readme_text =
+                                    format!("\r\n\r\n\r\n# \u{1F4D6} README\r\n\r\n\r\n{}", v);
+                                Some(readme_text)
+                            }
+                            Err(_) => None,
+                        },
                         Err(_) => None,
-                    },
-                    Err(_) => None,
-                };
-            }
+                    })
+            })
+            .unwrap_or(None);
         }
 
         match item.kind() {
             Some(ObjectType::Tree) => Some(TreeItem {
                 id: oid_str,
-                name: name,
+                name,
                 item_type: ItemType::Tree,
                 icon: String::from("\u{1F4C1}"),
                 content_string: contents,
             }),
             Some(ObjectType::Blob) => Some(TreeItem {
                 id: oid_str,
-                name: name,
+                name,
                 item_type: ItemType::Blob,
                 icon: String::from("\u{1F4C4}"),
                 content_string: contents,
@@ -142,52 +678,32 @@ impl<'a> Repo<'a> {
         let oid = Oid::from_str(hash)?;
         let object = self.repo.find_object(oid, Some(ObjectType::Tree))?;
 
-        let mut readme_text = None;
-        let tree = object
-            .peel_to_tree()?
-            .iter()
-            .filter_map(|i| match Repo::filter_tree(&self, i) {
-                Some(v) => {
-                    if v.name == "README.md" {
-                        readme_text = match &v.content_string {
-                            Some(text) => Some(String::from(text)),
-                            None => None,
-                        };
-                    };
-                    Some(v)
-                }
-                None => None,
-            })
-            .collect::<Vec<TreeItem>>();
-
-        Ok(TreeResponse {
-            tree: tree,
-            readme_text: readme_text,
-        })
+        self.list_tree(object.peel_to_tree()?)
     }
 
     fn get_ref_tree(&self, reference: &Reference) -> Result<TreeResponse, Box<dyn Error>> {
-        let mut readme_text = None;
-        let tree = reference
-            .peel_to_tree()?
-            .iter()
-            .filter_map(|i| match Repo::filter_tree(&self, i) {
-                Some(v) => {
-                    if v.name == "README.md" {
-                        readme_text = match &v.content_string {
-                            Some(text) => Some(String::from(text)),
-                            None => None,
+        self.list_tree(reference.peel_to_tree()?)
+    }
+
+    /// Lists a tree, cached by its id. Pages for branches and HEAD resolve the
+    /// ref on every request, so they see new commits as soon as refs change.
+    fn list_tree(&self, tree: git2::Tree) -> Result<TreeResponse, Box<dyn Error>> {
+        cache::get_or_insert(&TREES, (self.dir_path.clone(), tree.id()), || {
+            let mut readme_text = None;
+            let tree = tree
+                .iter()
+                .filter_map(|i| match Repo::filter_tree(self, i) {
+                    Some(v) => {
+                        if v.name == "README.md" {
+                            readme_text = v.content_string.as_ref().map(String::from);
                         };
-                    };
-                    Some(v)
-                }
-                None => None,
-            })
-            .collect::<Vec<TreeItem>>();
+                        Some(v)
+                    }
+                    None => None,
+                })
+                .collect::<Vec<TreeItem>>();
 
-        Ok(TreeResponse {
-            tree: tree,
-            readme_text: readme_text,
+            Ok(TreeResponse { tree, readme_text })
         })
     }
 
@@ -195,18 +711,70 @@ impl<'a> Repo<'a> {
         let head = self.repo.head()?;
         let active_branch = head.shorthand().unwrap();
 
-        let refs_path = format!("{}/refs/heads", self.dir_path);
-        let branches = util::get_files(&refs_path)?;
+        let branches = self
+            .repo
+            .branches(Some(BranchType::Local))?
+            .filter_map(|branch| {
+                branch
+                    .ok()
+                    .and_then(|(branch, _)| branch.name().ok().flatten().map(String::from))
+            })
+            .collect::<Vec<String>>();
 
         let tree_response = self.get_ref_tree(&head)?;
 
         Ok(RepoDetails {
             active_branch: String::from(active_branch),
-            branches: branches,
-            tree_response: tree_response,
+            branches,
+            is_bare: self.repo.is_bare(),
+            tree_response,
         })
     }
 
+    /// Lists the uncommitted changes in the working tree of a non-bare repo.
+    pub fn get_status(&self) -> Result<Vec<StatusItem>, Box<dyn Error>> {
+        if self.repo.is_bare() {
+            return Err("Bare repositories have no working tree".into());
+        }
+
+        let mut options = StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+
+        let states = [
+            (Status::INDEX_NEW, "staged new file"),
+            (Status::INDEX_MODIFIED, "staged modified"),
+            (Status::INDEX_DELETED, "staged deleted"),
+            (Status::INDEX_RENAMED, "staged renamed"),
+            (Status::INDEX_TYPECHANGE, "staged typechange"),
+            (Status::WT_NEW, "untracked"),
+            (Status::WT_MODIFIED, "modified"),
+            (Status::WT_DELETED, "deleted"),
+            (Status::WT_RENAMED, "renamed"),
+            (Status::WT_TYPECHANGE, "typechange"),
+            (Status::CONFLICTED, "conflicted"),
+        ];
+
+        let mut items = vec![];
+
+        for entry in self.repo.statuses(Some(&mut options))?.iter() {
+            let path = match entry.path() {
+                Some(path) => String::from(path),
+                None => continue,
+            };
+
+            for (status, state) in states.iter() {
+                if entry.status().contains(*status) {
+                    items.push(StatusItem {
+                        path: path.clone(),
+                        state: String::from(*state),
+                    });
+                }
+            }
+        }
+
+        Ok(items)
+    }
+
     pub fn get_branch_tree(&self, branch: &str) -> Result<TreeResponse, Box<dyn Error>> {
         let ref_path = format!("refs/heads/{}", branch);
 
@@ -216,80 +784,821 @@ impl<'a> Repo<'a> {
 
         Ok(tree_response)
     }
-}
 
-impl RepoDir {
-    pub fn new() -> RepoDir {
-        let repo_dir = env::var("REPO_DIR").unwrap_or_else(|err| {
-            panic!("Error reading REPO_DIR: {}", err);
-        });
+    /// Returns up to `limit` commits of a branch, newest first.
+    pub fn get_commits(
+        &self,
+        branch: &str,
+        limit: usize,
+    ) -> Result<Vec<CommitItem>, Box<dyn Error>> {
+        let head = self
+            .repo
+            .find_branch(branch, BranchType::Local)?
+            .get()
+            .peel_to_commit()?;
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(Sort::TIME)?;
+        revwalk.push(head.id())?;
+
+        revwalk
+            .take(limit)
+            .map(|oid| Ok(commit_item(&self.repo.find_commit(oid?)?)))
+            .collect()
+    }
+
+    /// Returns the newest commit across the local branches, with its branch.
+    pub fn get_latest_commit(&self) -> Result<Option<(String, CommitItem)>, Box<dyn Error>> {
+        let mut latest: Option<(String, Commit)> = None;
+
+        for branch in self.repo.branches(Some(BranchType::Local))? {
+            let (branch, _) = branch?;
+            let name = match branch.name()? {
+                Some(name) => String::from(name),
+                None => continue,
+            };
+            let commit = branch.get().peel_to_commit()?;
+
+            if latest
+                .as_ref()
+                .is_none_or(|(_, newest)| commit.time() > newest.time())
+            {
+                latest = Some((name, commit));
+            }
+        }
+
+        Ok(latest.map(|(branch, commit)| (branch, commit_item(&commit))))
+    }
+
+    /// Lists the tags pointing at commits, newest first. Annotated tags take
+    /// their message, author and time from the tag, and lightweight ones from
+    /// the commit.
+    pub fn get_tags(&self) -> Result<Vec<TagItem>, Box<dyn Error>> {
+        let mut tags = vec![];
+
+        for reference in self.repo.references_glob("refs/tags/*")? {
+            let reference = reference?;
+            let name = match reference.shorthand() {
+                Some(name) => String::from(name),
+                None => continue,
+            };
+            let commit = match reference.peel_to_commit() {
+                Ok(commit) => commit,
+                Err(_) => continue,
+            };
+
+            let (message, signature) = match reference.peel_to_tag() {
+                Ok(tag) => (
+                    tag.message().unwrap_or("").trim().to_string(),
+                    tag.tagger()
+                        .map(|tagger| tagger.to_owned())
+                        .unwrap_or_else(|| commit.author().to_owned()),
+                ),
+                Err(_) => (
+                    commit.message().unwrap_or("").trim().to_string(),
+                    commit.author().to_owned(),
+                ),
+            };
+
+            tags.push((
+                signature.when().seconds(),
+                TagItem {
+                    name,
+                    tree: commit.tree_id().to_string(),
+                    message,
+                    author: String::from(signature.name().unwrap_or("")),
+                    time: format_time(signature.when()),
+                    date: format_date(signature.when()),
+                },
+            ));
+        }
+
+        tags.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        Ok(tags.into_iter().map(|(_, tag)| tag).collect())
+    }
+
+    /// Commits a new version of the file at `path` on a branch, or removes it
+    /// when `content` is `None`, with `name` and `email` as author and
+    /// committer. The branch is only moved if it still points at the commit the
+    /// change was based on, so an update racing another one is refused instead
+    /// of replacing it, and branches checked out in a working tree aren't
+    /// changed.
+    pub fn commit_file(
+        &self,
+        branch: &str,
+        path: &[String],
+        content: Option<&[u8]>,
+        name: &str,
+        email: &str,
+    ) -> Result<Oid, Box<dyn Error>> {
+        let reference = self
+            .repo
+            .find_branch(branch, BranchType::Local)?
+            .into_reference();
+        let ref_name = reference.name().ok_or("Branch name is not valid UTF-8")?;
+
+        if !self.repo.is_bare() && self.repo.head()?.name() == Some(ref_name) {
+            return Err(format!("{} is checked out in the working tree", branch).into());
+        }
 
-        let config: util::Config = match confy::load("gemini-git-browser") {
-            Ok(cfg) => cfg,
-            Err(e) => panic!("Config read error: {}", e),
+        let parent = reference.peel_to_commit()?;
+        let tree = match self.update_tree(Some(parent.tree()?), path, content)? {
+            Some(tree) => tree,
+            None => self.repo.treebuilder(None)?.write()?,
         };
 
+        if tree == parent.tree_id() {
+            return Err("The file is unchanged".into());
+        }
+
+        let message = match content {
+            Some(_) => format!("Update {}", path.join("/")),
+            None => format!("Delete {}", path.join("/")),
+        };
+        let signature = Signature::now(name, email)?;
+        let tree = self.repo.find_tree(tree)?;
+        let commit = self
+            .repo
+            .commit(None, &signature, &signature, &message, &tree, &[&parent])?;
+
+        self.repo
+            .reference_matching(ref_name, commit, true, parent.id(), &message)
+            .map_err(|_| {
+                format!(
+                    "{} was updated meanwhile, this isn't a fast-forward",
+                    branch
+                )
+            })?;
+
+        Ok(commit)
+    }
+
+    /// Writes a copy of `tree` with the file at `path` replaced or removed,
+    /// returning `None` when nothing is left in it.
+    fn update_tree(
+        &self,
+        tree: Option<Tree>,
+        path: &[String],
+        content: Option<&[u8]>,
+    ) -> Result<Option<Oid>, Box<dyn Error>> {
+        let (name, rest) = path.split_first().ok_or("No file path given")?;
+        let mut builder = self.repo.treebuilder(tree.as_ref())?;
+        let existing = tree
+            .as_ref()
+            .and_then(|tree| tree.get_name(name))
+            .map(|entry| (entry.id(), entry.filemode()));
+
+        if rest.is_empty() {
+            let mode = match existing {
+                None => i32::from(FileMode::Blob),
+                Some((_, mode))
+                    if mode == i32::from(FileMode::Blob)
+                        || mode == i32::from(FileMode::BlobExecutable) =>
+                {
+                    mode
+                }
+                Some(_) => return Err(format!("{} is not a regular file", name).into()),
+            };
+
+            match content {
+                Some(content) => {
+                    builder.insert(name, self.repo.blob(content)?, mode)?;
+                }
+                None if existing.is_some() => builder.remove(name)?,
+                None => return Err(format!("{} does not exist", name).into()),
+            }
+        } else {
+            let subtree = match existing {
+                Some((id, mode)) if mode == i32::from(FileMode::Tree) => {
+                    Some(self.repo.find_tree(id)?)
+                }
+                Some(_) => return Err(format!("{} is not a directory", name).into()),
+                None => None,
+            };
+
+            match self.update_tree(subtree, rest, content)? {
+                Some(id) => {
+                    builder.insert(name, id, i32::from(FileMode::Tree))?;
+                }
+                None => builder.remove(name)?,
+            }
+        }
+
+        match builder.len() {
+            0 => Ok(None),
+            _ => Ok(Some(builder.write()?)),
+        }
+    }
+
+    /// Splits the segments following a tree id into the branch they were
+    /// reached from and the path of the tree within it.
+    pub fn split_branch(&self, segments: &[String]) -> Option<(String, Vec<String>)> {
+        (1..=segments.len()).find_map(|depth| {
+            let branch = segments[..depth].join("/");
+
+            match self.repo.find_branch(&branch, BranchType::Local) {
+                Ok(_) => Some((branch, segments[depth..].to_vec())),
+                Err(_) => None,
+            }
+        })
+    }
+
+    /// Returns the tree ids of each directory along `path` in a branch.
+    pub fn get_path_ids(
+        &self,
+        branch: &str,
+        path: &[String],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let tree = self
+            .repo
+            .find_branch(branch, BranchType::Local)?
+            .get()
+            .peel_to_tree()?;
+
+        (1..=path.len())
+            .map(|depth| {
+                let entry = tree.get_path(Path::new(&path[..depth].join("/")))?;
+                Ok(entry.id().to_string())
+            })
+            .collect()
+    }
+
+    /// Lists what goes into the archive of `reference`, which is written out
+    /// later by `write_archive`, so a missing ref is found before streaming.
+    pub fn get_archive(
+        &self,
+        reference: &str,
+        format: ArchiveFormat,
+    ) -> Result<Archive, Box<dyn Error>> {
+        let commit = self.repo.revparse_single(reference)?.peel_to_commit()?;
+        let mtime = commit.time().seconds();
+
+        let prefix = format!("{}-{}/", self.name, reference.replace('/', "-"));
+        let mut entries = vec![ArchiveEntry {
+            path: prefix.clone(),
+            id: commit.tree_id(),
+            mode: 0o040000,
+            kind: ObjectType::Tree,
+        }];
+
+        commit.tree()?.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if let (Some(name), Some(kind)) = (entry.name(), entry.kind()) {
+                let path = match kind {
+                    ObjectType::Tree => format!("{}{}{}/", prefix, root, name),
+                    _ => format!("{}{}{}", prefix, root, name),
+                };
+
+                entries.push(ArchiveEntry {
+                    path,
+                    id: entry.id(),
+                    mode: entry.filemode(),
+                    kind,
+                });
+            }
+            TreeWalkResult::Ok
+        })?;
+
+        Ok(Archive {
+            format,
+            entries,
+            mtime,
+        })
+    }
+
+    /// Writes an archive to `out`, reading one blob at a time.
+    pub fn write_archive(&self, archive: &Archive, out: impl Write) -> Result<(), Box<dyn Error>> {
+        match archive.format {
+            ArchiveFormat::TarGz => self.write_tar_gz(&archive.entries, archive.mtime, out),
+            ArchiveFormat::Zip => self.write_zip(&archive.entries, archive.mtime, out),
+        }
+    }
+
+    fn write_tar_gz(
+        &self,
+        entries: &[ArchiveEntry],
+        mtime: i64,
+        out: impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut builder = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(mtime.max(0) as u64);
+
+            match (entry.kind, entry.mode) {
+                (ObjectType::Tree, _) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, &entry.path, io::empty())?;
+                }
+                (ObjectType::Blob, 0o120000) => {
+                    let blob = self.repo.find_blob(entry.id)?;
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder.append_link(
+                        &mut header,
+                        &entry.path,
+                        str::from_utf8(blob.content())?,
+                    )?;
+                }
+                (ObjectType::Blob, mode) => {
+                    let blob = self.repo.find_blob(entry.id)?;
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(if mode == 0o100755 { 0o755 } else { 0o644 });
+                    header.set_size(blob.content().len() as u64);
+                    builder.append_data(&mut header, &entry.path, blob.content())?;
+                }
+                _ => continue,
+            }
+        }
+
+        builder.into_inner()?.finish()?.flush()?;
+
+        Ok(())
+    }
+
+    /// Zip writers go back to each file header once its data is written, so
+    /// the archive is built in an unlinked temporary file before it is copied
+    /// to `out`.
+    fn write_zip(
+        &self,
+        entries: &[ArchiveEntry],
+        mtime: i64,
+        mut out: impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut writer = zip::ZipWriter::new(temp_file()?);
+
+        let mut options = zip::write::FileOptions::default();
+        if let Some(time) = DateTime::<Utc>::from_timestamp(mtime, 0) {
+            if let Ok(time) = zip::DateTime::from_date_and_time(
+                time.year() as u16,
+                time.month() as u8,
+                time.day() as u8,
+                time.hour() as u8,
+                time.minute() as u8,
+                time.second() as u8,
+            ) {
+                options = options.last_modified_time(time);
+            }
+        }
+
+        for entry in entries {
+            match (entry.kind, entry.mode) {
+                (ObjectType::Tree, _) => {
+                    writer.add_directory(entry.path.as_str(), options.unix_permissions(0o755))?;
+                }
+                (ObjectType::Blob, 0o120000) => {
+                    let blob = self.repo.find_blob(entry.id)?;
+                    writer.add_symlink(
+                        entry.path.as_str(),
+                        str::from_utf8(blob.content())?,
+                        options,
+                    )?;
+                }
+                (ObjectType::Blob, mode) => {
+                    let blob = self.repo.find_blob(entry.id)?;
+                    let permissions = if mode == 0o100755 { 0o755 } else { 0o644 };
+                    writer
+                        .start_file(entry.path.as_str(), options.unix_permissions(permissions))?;
+                    writer.write_all(blob.content())?;
+                }
+                _ => continue,
+            }
+        }
+
+        let mut file = writer.finish()?;
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut file, &mut out)?;
+        out.flush()?;
+
+        Ok(())
+    }
+
+    pub fn get_info_refs(&self) -> Result<String, Box<dyn Error>> {
+        let mut refs = vec![];
+
+        for reference in self.repo.references()? {
+            let reference = reference?;
+            let (name, target) = match (reference.name(), reference.target()) {
+                (Some(name), Some(target)) => (String::from(name), target),
+                _ => continue,
+            };
+
+            refs.push(format!("{}\t{}\n", target, name));
+
+            // Tags of tags are peeled all the way to the object they tag.
+            if let Ok(peeled) = reference.peel(ObjectType::Any) {
+                if peeled.id() != target {
+                    refs.push(format!("{}\t{}^{{}}\n", peeled.id(), name));
+                }
+            }
+        }
+
+        refs.sort_by(|a, b| a.split('\t').nth(1).cmp(&b.split('\t').nth(1)));
+
+        Ok(refs.concat())
+    }
+
+    pub fn get_head_ref(&self) -> Result<String, Box<dyn Error>> {
+        let head = self.repo.find_reference("HEAD")?;
+
+        match (head.symbolic_target(), head.target()) {
+            (Some(target), _) => Ok(format!("ref: {}\n", target)),
+            (None, Some(oid)) => Ok(format!("{}\n", oid)),
+            _ => Err("HEAD is not set".into()),
+        }
+    }
+
+    pub fn get_info_packs(&self) -> Result<String, Box<dyn Error>> {
+        let pack_dir = self.repo.path().join("objects/pack");
+
+        let mut packs = match pack_dir.to_str() {
+            Some(dir) => util::get_files(dir).unwrap_or_default(),
+            None => vec![],
+        }
+        .into_iter()
+        .filter(|name| name.ends_with(".pack"))
+        .map(|name| format!("P {}\n", name))
+        .collect::<Vec<String>>();
+
+        packs.sort();
+        packs.push(String::from("\n"));
+
+        Ok(packs.concat())
+    }
+
+    /// Checks that an object is in the repo, loose or packed.
+    pub fn has_object(&self, hash: &str) -> bool {
+        match (Oid::from_str(hash), self.repo.odb()) {
+            (Ok(oid), Ok(odb)) => odb.exists(oid),
+            _ => false,
+        }
+    }
+
+    /// Writes an object to `out` the way git stores it loose, compressed with
+    /// its header. Objects that `object_reader` can't open are read whole, so
+    /// they are refused above `max_blob_size`.
+    pub fn write_loose_object(&self, hash: &str, out: impl Write) -> Result<(), Box<dyn Error>> {
+        let oid = Oid::from_str(hash)?;
+        let odb = self.repo.odb()?;
+        let mut encoder = ZlibEncoder::new(out, Compression::default());
+
+        match self.object_reader(&odb, oid)? {
+            Some((reader, size, kind)) => {
+                write!(encoder, "{} {}\0", kind.str(), size)?;
+                // libgit2 keeps returning data past the end of a loose object,
+                // so stop after the size it reported.
+                io::copy(&mut reader.take(size as u64), &mut encoder)?;
+            }
+            None => {
+                if odb.read_header(oid)?.0 > self.repo_dir.config.max_blob_size {
+                    return Err(format!("Object {} is too large to read whole", hash).into());
+                }
+
+                let object = odb.read(oid)?;
+                write!(encoder, "{} {}\0", object.kind().str(), object.len())?;
+                encoder.write_all(object.data())?;
+            }
+        }
+
+        encoder.finish()?.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the path of a pack or pack index of the repo, from its name.
+    pub fn get_pack_path(&self, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let is_pack_file = name.strip_prefix("pack-").is_some_and(|rest| {
+            let hash = rest
+                .strip_suffix(".pack")
+                .or_else(|| rest.strip_suffix(".idx"))
+                .unwrap_or("");
+
+            hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit())
+        });
+
+        if !is_pack_file {
+            return Err(format!("No such pack file: {}", name).into());
+        }
+
+        let path = self.repo.path().join("objects/pack").join(name);
+
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(format!("No such pack file: {}", name).into()),
+        }
+    }
+}
+
+impl RepoDir {
+    pub fn with_config(mut config: util::Config) -> RepoDir {
+        if config.discover {
+            config.discovered = discover_repos(Path::new(&config.repo_dir), "");
+        }
+
         RepoDir {
-            dir_path: repo_dir,
-            config: config,
+            dir_path: config.repo_dir.clone(),
+            root: fs::canonicalize(&config.repo_dir).ok(),
+            config,
         }
     }
 
+    /// Checks that every `allowed` entry exists, namespaces as directories and
+    /// repos as git repositories, and that every pattern matches something,
+    /// returning a description of each problem.
+    pub fn check_allowed(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        for entry in &self.config.allowed {
+            if entry.ends_with('/') || entry.contains(['*', '?', '[']) {
+                let pattern = format!("{}/{}", self.dir_path, entry.trim_end_matches('/'));
+                let matches = glob::glob(&pattern).map(|mut paths| paths.next().is_some());
+
+                if let Ok(false) = matches {
+                    problems.push(format!("allowed rule `{}` does not match anything", entry));
+                }
+                continue;
+            }
+
+            let path = format!("{}/{}", self.dir_path, entry);
+
+            if !Path::new(&path).is_dir() {
+                problems.push(format!(
+                    "allowed entry `{}`: `{}` is not a directory",
+                    entry, path
+                ));
+                continue;
+            }
+
+            if entry.ends_with(".git") || util::is_repo_dir(&path) {
+                if let Err(err) = Repository::open(&path) {
+                    problems.push(format!(
+                        "allowed entry `{}`: `{}` is not a git repository: {}",
+                        entry,
+                        path,
+                        err.message()
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
     fn get_repo_dir(&self, repo_path: &str) -> Result<String, Box<dyn Error>> {
-        if !self.config.check_dir(repo_path) {
-            Err(format!("No such repo: {}", repo_path).into())
-        } else {
-            Ok(format!("{}/{}", self.dir_path, repo_path))
+        match self.config.check_dir(repo_path) {
+            true => self
+                .resolve(repo_path)
+                .ok_or_else(|| format!("No such repo: {}", repo_path).into()),
+            false => Err(format!("No such repo: {}", repo_path).into()),
+        }
+    }
+
+    /// Returns the path, relative to the repo dir, that a namespace or repo
+    /// path leads to once symlinks are followed, as long as every segment is a
+    /// plain name and the result is still inside the repo dir.
+    fn canonical_path(&self, path: &str) -> Option<String> {
+        let root = self.root.as_ref()?;
+
+        if !path.split('/').all(util::is_safe_segment) {
+            return None;
+        }
+
+        let dir = fs::canonicalize(root.join(path)).ok()?;
+
+        match dir.strip_prefix(root).ok()?.to_str() {
+            Some(canonical) if !canonical.is_empty() => Some(String::from(canonical)),
+            _ => None,
         }
     }
 
-    pub fn get_ns_repos(&self, ns: &str) -> Result<Vec<String>, Box<dyn Error>> {
-        let dir = self.get_repo_dir(ns)?;
-        let dirs = util::get_dirs(&dir)?;
+    /// Resolves a namespace or repo path to its canonical directory. When
+    /// symlinks lead to another path, the rules have to serve that path too,
+    /// so a link can't expose a repo they leave out.
+    fn resolve(&self, path: &str) -> Option<String> {
+        let canonical = self.canonical_path(path)?;
+
+        if canonical != path && !self.config.check_dir(&canonical) {
+            return None;
+        }
 
-        let dirs = dirs
+        let dir = self.root.as_ref()?.join(&canonical);
+
+        match dir.is_dir() {
+            true => dir.to_str().map(String::from),
+            false => None,
+        }
+    }
+
+    /// Checks the client certificate against the rules for a path, and for
+    /// the path its symlinks lead to.
+    fn check_cert(&self, path: &str, fingerprint: Option<&str>) -> util::Access {
+        let access = self.config.check_cert(path, fingerprint);
+
+        match self.canonical_path(path) {
+            Some(canonical) if canonical != path && access == util::Access::Allowed => {
+                self.config.check_cert(&canonical, fingerprint)
+            }
+            _ => access,
+        }
+    }
+
+    /// Finds the served group or repo that request path segments point to.
+    /// The repo path ends at the first segment that is a git repository, and
+    /// the segments following it are returned along with it. Private paths
+    /// return the certificate status when `fingerprint` isn't authorized.
+    pub fn locate(
+        &self,
+        segments: &[String],
+        fingerprint: Option<&str>,
+    ) -> Result<Location, Access> {
+        let mut location = None;
+
+        if !segments
             .iter()
-            .filter_map(|repo| {
-                let repo_path = format!("{}/{}", ns, repo);
-                match self.config.check_dir(repo_path.as_str()) {
-                    true => Some(String::from(repo)),
-                    false => None,
+            .all(|segment| util::is_safe_segment(segment))
+        {
+            return Err(Access::NotFound);
+        }
+
+        for depth in 1..=segments.len() {
+            let path = segments[..depth].join("/");
+
+            if !self.config.check_dir(&path) {
+                return Err(Access::NotFound);
+            }
+
+            let dir = match self.resolve(&path) {
+                Some(dir) => dir,
+                None => return Err(Access::NotFound),
+            };
+
+            if util::is_repo_dir(&dir) {
+                location = Some(Location::Repo(path, segments[depth..].to_vec()));
+                break;
+            }
+        }
+
+        let location = match location {
+            Some(location) => location,
+            None => {
+                let path = segments.join("/");
+
+                match self.resolve(&path) {
+                    Some(_) => Location::Group(path),
+                    None => return Err(Access::NotFound),
                 }
-            })
-            .collect::<Vec<String>>();
+            }
+        };
+
+        let path = match &location {
+            Location::Group(path) | Location::Repo(path, _) => path,
+        };
 
-        Ok(dirs)
+        match self.check_cert(path, fingerprint) {
+            util::Access::Allowed => Ok(location),
+            util::Access::CertificateRequired => Err(Access::CertificateRequired),
+            util::Access::NotAuthorized => Err(Access::NotAuthorized),
+        }
     }
 
-    pub fn get_repos(&self) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
-        let ns_dirs = util::get_dirs(&self.dir_path)?;
+    /// Lists the served sub-groups and repos directly inside a group, or
+    /// inside the repo dir itself when `path` is empty, leaving out private
+    /// ones `fingerprint` isn't authorized for.
+    pub fn get_group(
+        &self,
+        path: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<Group, Box<dyn Error>> {
+        let dir = match path.is_empty() {
+            true => self.dir_path.clone(),
+            false => self.get_repo_dir(path)?,
+        };
+
+        let mut group = Group {
+            groups: vec![],
+            repos: vec![],
+        };
 
-        let mut repos: HashMap<String, Vec<String>> = HashMap::new();
+        for name in util::get_dirs(&dir)? {
+            let child_path = match path.is_empty() {
+                true => name.clone(),
+                false => format!("{}/{}", path, name),
+            };
 
-        for ns_dir in ns_dirs {
-            let ns_repo_dir = match self.get_repo_dir(ns_dir.as_str()) {
-                Ok(dir) => dir,
-                Err(_) => continue,
+            if !self.config.check_dir(&child_path)
+                || self.check_cert(&child_path, fingerprint) != util::Access::Allowed
+            {
+                continue;
+            }
+
+            let child_dir = match self.resolve(&child_path) {
+                Some(child_dir) => child_dir,
+                None => continue,
             };
-            match util::get_dirs(&ns_repo_dir) {
-                Ok(result) => {
-                    let result = result
-                        .iter()
-                        .filter_map(|repo| {
-                            let repo_path = format!("{}/{}", &ns_dir, repo);
-                            match self.config.check_dir(repo_path.as_str()) {
-                                true => Some(String::from(repo)),
-                                false => None,
-                            }
-                        })
-                        .collect::<Vec<String>>();
-                    &repos.insert(String::from(ns_dir), result);
-                    ()
-                }
-                Err(e) => return Err(format!("Error reading REPO_DIR: {}", e).into()),
+
+            match util::is_repo_dir(&child_dir) {
+                true => group.repos.push(name),
+                false => group.groups.push(name),
             }
         }
 
+        group.groups.sort();
+        group.repos.sort();
+
+        Ok(group)
+    }
+
+    /// Lists the paths of every served repo, at any depth.
+    pub fn get_repos(&self, fingerprint: Option<&str>) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut repos = vec![];
+        let mut groups = vec![String::new()];
+
+        while let Some(path) = groups.pop() {
+            let group = match self.get_group(&path, fingerprint) {
+                Ok(group) => group,
+                Err(e) => return Err(format!("Error reading REPO_DIR: {}", e).into()),
+            };
+            let prefix = match path.is_empty() {
+                true => String::new(),
+                false => format!("{}/", path),
+            };
+
+            repos.extend(group.repos.iter().map(|repo| format!("{}{}", prefix, repo)));
+            groups.extend(group.groups.iter().map(|sub| format!("{}{}", prefix, sub)));
+        }
+
+        repos.sort();
+
         Ok(repos)
     }
 }
+
+fn commit_item(commit: &Commit) -> CommitItem {
+    CommitItem {
+        id: commit.id().to_string(),
+        tree: commit.tree_id().to_string(),
+        summary: String::from(commit.summary().unwrap_or("")),
+        message: commit.message().unwrap_or("").trim().to_string(),
+        author: String::from(commit.author().name().unwrap_or("")),
+        time: format_time(commit.time()),
+        date: format_date(commit.time()),
+    }
+}
+
+/// Formats a git time as an RFC 3339 timestamp in UTC.
+fn format_time(time: Time) -> String {
+    DateTime::<Utc>::from_timestamp(time.seconds(), 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Formats the UTC date of a git time as `YYYY-MM-DD`.
+fn format_date(time: Time) -> String {
+    format_time(time)[..10].to_string()
+}
+
+/// Walks `dir` and returns the paths, relative to the repo dir, of every repo
+/// that opted into being served with a `git-daemon-export-ok` file in its git
+/// dir or the `gemini.export` config key, which takes precedence when set.
+/// Repos aren't descended into and symlinks aren't followed.
+fn discover_repos(dir: &Path, prefix: &str) -> Vec<String> {
+    let mut repos = vec![];
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return repos,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = format!("{}{}", prefix, name);
+
+        match Repository::open(entry.path()) {
+            Ok(repo) => {
+                let exported = repo
+                    .config()
+                    .and_then(|config| config.get_bool("gemini.export"))
+                    .unwrap_or_else(|_| repo.path().join("git-daemon-export-ok").is_file());
+
+                if exported {
+                    repos.push(path);
+                }
+            }
+            Err(_) => repos.extend(discover_repos(&entry.path(), &format!("{}/", path))),
+        }
+    }
+
+    repos.sort();
+    repos
+}
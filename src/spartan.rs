@@ -0,0 +1,168 @@
+use anyhow::Context;
+use futures_core::future::BoxFuture;
+use futures_util::FutureExt;
+use northstar::{uri::URIReference, Body, Response};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::{convert::TryFrom, net::SocketAddr, time::Duration};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    actions,
+    listener::{self, Protocol, Received, Stream},
+};
+
+/// Longest request line accepted.
+const REQUEST_MAX_LEN: usize = 1024;
+
+/// Most input accepted with a request, which is passed on as the query.
+const INPUT_MAX_LEN: usize = 1024;
+
+/// Serves the pages over Spartan on `addr`. Input is sent as the query of
+/// the Gemini request.
+pub async fn serve(
+    addr: SocketAddr,
+    timeout: Duration,
+    download_timeout: Duration,
+) -> anyhow::Result<()> {
+    listener::serve::<Spartan>(addr, timeout, download_timeout).await
+}
+
+struct Spartan;
+
+impl Protocol for Spartan {
+    const NAME: &'static str = "Spartan";
+
+    type Context = ();
+
+    fn receive(stream: &mut Stream) -> BoxFuture<'_, anyhow::Result<Received<()>>> {
+        async move {
+            let (host, path, input) = match receive_request(stream).await {
+                Ok(request) => request,
+                Err(message) => return send_status(stream, 4, &message).await.map(|_| None),
+            };
+
+            let target = match input.is_empty() {
+                true => path,
+                false => format!("{}?{}", path, utf8_percent_encode(&input, NON_ALPHANUMERIC)),
+            };
+
+            match actions::gemini_request(&host, &target) {
+                Some(request) => Ok(Some((request, ()))),
+                None => send_status(stream, 4, "Not a valid request")
+                    .await
+                    .map(|_| None),
+            }
+        }
+        .boxed()
+    }
+
+    fn send(stream: &mut Stream, response: Response, _: ()) -> BoxFuture<'_, anyhow::Result<()>> {
+        send_response(stream, response).boxed()
+    }
+}
+
+/// Reads a request line and the input following it, or returns the message
+/// refusing it.
+async fn receive_request(
+    stream: &mut (impl AsyncBufRead + Unpin),
+) -> Result<(String, String, String), String> {
+    let mut line = vec![];
+
+    (&mut *stream)
+        .take(REQUEST_MAX_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|_| String::from("Failed to read the request"))?;
+
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or("Request line is too long or not UTF-8")?;
+
+    let (host, path, size) = parse_request(line)?;
+
+    if size > INPUT_MAX_LEN {
+        return Err(format!("Input is limited to {} bytes", INPUT_MAX_LEN));
+    }
+
+    let mut input = vec![0; size];
+    stream
+        .read_exact(&mut input)
+        .await
+        .map_err(|_| String::from("Input is shorter than its length"))?;
+
+    let input = String::from_utf8(input).map_err(|_| String::from("Input is not UTF-8"))?;
+
+    Ok((host, path, input))
+}
+
+/// Parses a request line, `host path content-length`, into its parts.
+pub(crate) fn parse_request(line: &str) -> Result<(String, String, usize), String> {
+    let mut parts = line.split(' ');
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(path), Some(size), None) if path.starts_with('/') => {
+            let size = size
+                .parse::<usize>()
+                .map_err(|_| String::from("Content length is not a number"))?;
+
+            Ok((String::from(host), String::from(path), size))
+        }
+        _ => Err(String::from("Not a valid request")),
+    }
+}
+
+/// Returns the path a Gemini redirect points to, as Spartan redirects stay
+/// on the same host.
+pub(crate) fn redirect_path(location: &str) -> String {
+    let uri = match URIReference::try_from(location) {
+        Ok(uri) if uri.has_scheme() => uri,
+        _ => return String::from(location),
+    };
+
+    match uri.query() {
+        Some(query) => format!("{}?{}", uri.path(), query),
+        None => uri.path().to_string(),
+    }
+}
+
+async fn send_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut response: Response,
+) -> anyhow::Result<()> {
+    let code = response.header().status.code();
+    let meta = String::from(response.header().meta.as_str());
+
+    match code {
+        20..=29 => {
+            send_status(stream, 2, &meta).await?;
+
+            match response.take_body() {
+                Some(Body::Bytes(bytes)) => stream.write_all(&bytes).await?,
+                Some(Body::Reader(mut reader)) => {
+                    io::copy(&mut reader, stream).await?;
+                }
+                None => {}
+            }
+        }
+        30..=39 => send_status(stream, 3, &redirect_path(&meta)).await?,
+        40..=49 => send_status(stream, 5, &meta).await?,
+        _ => send_status(stream, 4, &meta).await?,
+    }
+
+    stream.flush().await.context("Failed to send response")
+}
+
+async fn send_status(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u8,
+    meta: &str,
+) -> anyhow::Result<()> {
+    let line = format!("{} {}\r\n", status, meta);
+
+    stream
+        .write_all(line.as_bytes())
+        .await
+        .context("Failed to send response")?;
+    stream.flush().await.context("Failed to send response")
+}
@@ -1,5 +1,21 @@
-use crate::util::md_to_gemtext;
-use std::{fs::File, io::Read};
+use crate::{
+    actions::{self, request_segments, tree_context},
+    cache::Lru,
+    http,
+    repo::{self, ArchiveFormat, LfsPointer, Location, RepoDir},
+    spartan, titan,
+    util::{
+        cert_fingerprint, gemtext_to_html, is_repo_dir, is_safe_segment, md_to_gemtext, Access,
+        Config,
+    },
+};
+use git2::{Commit, Oid, Repository, Signature, Time};
+use northstar::{uri::URIReference, Body, Request, Response};
+use serde_json::{json, Value};
+use std::{
+    convert::TryFrom, env, fs, fs::File, io, io::Read, io::Write, os::unix::fs::symlink,
+    path::Path, path::PathBuf, process, sync::Once,
+};
 
 #[test]
 fn test_md_to_gemtext() {
@@ -17,3 +33,1658 @@ fn test_md_to_gemtext() {
 
     assert_eq!(gem_buf, gemtext);
 }
+
+#[test]
+fn test_archive_format_from_file_name() {
+    assert_eq!(
+        ArchiveFormat::from_file_name("master.tar.gz"),
+        Some(("master", ArchiveFormat::TarGz))
+    );
+    assert_eq!(
+        ArchiveFormat::from_file_name("feature/x.zip"),
+        Some(("feature/x", ArchiveFormat::Zip))
+    );
+    assert_eq!(ArchiveFormat::from_file_name("master.tar"), None);
+}
+
+#[test]
+fn test_get_clone_urls() {
+    let mut config = Config {
+        clone_urls: vec![String::from("https://git.example.com/{ns}/{repo}")],
+        ..Default::default()
+    };
+    config.ns_clone_urls.insert(
+        String::from("masalachai"),
+        vec![String::from("ssh://git@github.com/{ns}/{name}")],
+    );
+
+    assert_eq!(
+        config.get_clone_urls("ritesh", "epp-client.git"),
+        vec!["https://git.example.com/ritesh/epp-client.git"]
+    );
+    assert_eq!(
+        config.get_clone_urls("masalachai", "gemini-git-browser.git"),
+        vec!["ssh://git@github.com/masalachai/gemini-git-browser"]
+    );
+    assert_eq!(
+        config.get_clone_urls("", "top.git"),
+        vec!["https://git.example.com/top.git"]
+    );
+    assert_eq!(
+        config.get_clone_urls("org/team", "repo.git"),
+        vec!["https://git.example.com/org/team/repo.git"]
+    );
+}
+
+#[test]
+fn test_listen_addrs() {
+    let config = Config {
+        port: 1966,
+        bind: vec![
+            String::from("0.0.0.0"),
+            String::from("::1"),
+            String::from("[::]:1967"),
+        ],
+        ..Default::default()
+    };
+
+    let addrs = config
+        .listen_addrs()
+        .unwrap()
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<String>>();
+
+    assert_eq!(addrs, vec!["0.0.0.0:1966", "[::1]:1966", "[::]:1967"]);
+
+    let config = Config {
+        bind: vec![String::from("localhost")],
+        ..Default::default()
+    };
+
+    assert!(config.listen_addrs().is_err());
+}
+
+#[test]
+fn test_check_dir_rules() {
+    let rules = |allowed: &[&str], denied: &[&str]| Config {
+        allowed: allowed.iter().map(|r| String::from(*r)).collect(),
+        denied: denied.iter().map(|r| String::from(*r)).collect(),
+        ..Default::default()
+    };
+
+    let config = rules(&["ritesh", "ritesh/epp-client.git"], &[]);
+    assert!(config.check_dir("ritesh"));
+    assert!(config.check_dir("ritesh/epp-client.git"));
+    assert!(!config.check_dir("ritesh/deploy-scripts.git"));
+    assert!(!config.check_dir("chitlangi"));
+
+    // A repo pattern also allows its namespace to be listed
+    let config = rules(&["ritesh/*", "*/public-*"], &[]);
+    assert!(config.check_dir("ritesh"));
+    assert!(config.check_dir("ritesh/deploy-scripts.git"));
+    assert!(config.check_dir("chitlangi"));
+    assert!(config.check_dir("chitlangi/public-notes.git"));
+    assert!(!config.check_dir("chitlangi/ritesh.ch.git"));
+    assert!(!config.check_dir("ritesh/sub/deep.git"));
+
+    // Whole namespace shorthand
+    let config = rules(&["ritesh/"], &[]);
+    assert!(config.check_dir("ritesh"));
+    assert!(config.check_dir("ritesh/epp-client.git"));
+    assert!(!config.check_dir("ritesh-other"));
+
+    // Denied rules take precedence, and denying a namespace denies its repos
+    let config = rules(
+        &["ritesh/", "chitlangi/*"],
+        &["ritesh/*-private.git", "chitlangi"],
+    );
+    assert!(config.check_dir("ritesh/epp-client.git"));
+    assert!(!config.check_dir("ritesh/notes-private.git"));
+    assert!(!config.check_dir("chitlangi"));
+    assert!(!config.check_dir("chitlangi/ritesh.ch.git"));
+
+    let config = rules(&["*/*"], &["*/secret.git"]);
+    assert!(config.check_dir("ritesh/epp-client.git"));
+    assert!(!config.check_dir("ritesh/secret.git"));
+}
+
+#[test]
+fn test_check_dir_discovered() {
+    let config = Config {
+        allowed: vec![String::from("ritesh/epp-client.git")],
+        denied: vec![String::from("*/secret.git")],
+        discovered: vec![
+            String::from("chitlangi/ritesh.ch.git"),
+            String::from("chitlangi/secret.git"),
+        ],
+        ..Default::default()
+    };
+
+    assert!(config.check_dir("chitlangi"));
+    assert!(config.check_dir("chitlangi/ritesh.ch.git"));
+    assert!(!config.check_dir("chitlangi/secret.git"));
+    assert!(!config.check_dir("chitlangi/ritesh.ch"));
+    assert!(!config.check_dir("chitlangi/other.git"));
+    assert!(config.check_dir("ritesh/epp-client.git"));
+}
+
+#[test]
+fn test_check_cert() {
+    let fingerprint = cert_fingerprint(b"certificate");
+    let mut config = Config {
+        allowed: vec![String::from("ritesh/*")],
+        private: vec![String::from("ritesh/secret.git"), String::from("team/")],
+        ..Default::default()
+    };
+    config
+        .certificates
+        .insert(fingerprint.to_uppercase(), String::from("ritesh"));
+    config.users.insert(
+        String::from("ritesh"),
+        vec![String::from("ritesh/secret.git")],
+    );
+
+    assert_eq!(fingerprint.len(), 64);
+    assert_eq!(
+        config.check_cert("ritesh/epp-client.git", None),
+        Access::Allowed
+    );
+    assert_eq!(
+        config.check_cert("ritesh/secret.git", None),
+        Access::CertificateRequired
+    );
+    assert_eq!(
+        config.check_cert("ritesh/secret.git", Some(&fingerprint)),
+        Access::Allowed
+    );
+    assert_eq!(
+        config.check_cert("ritesh/secret.git", Some("00")),
+        Access::NotAuthorized
+    );
+    assert_eq!(
+        config.check_cert("team/repo.git", Some(&fingerprint)),
+        Access::NotAuthorized
+    );
+    assert_eq!(
+        config.check_cert("team", Some(&fingerprint)),
+        Access::NotAuthorized
+    );
+    assert!(config.check_dir("team/repo.git"));
+}
+
+#[test]
+fn test_hostile_paths() {
+    let dir = env::temp_dir().join(format!("gemini-git-browser-test-{}", process::id()));
+    let root = dir.join("repos");
+    fs::create_dir_all(root.join("ns")).unwrap();
+    fs::create_dir_all(root.join("hidden")).unwrap();
+    Repository::init_bare(root.join("ns/repo.git")).unwrap();
+    Repository::init_bare(root.join("ns/denied.git")).unwrap();
+    Repository::init_bare(root.join("ns/secret.git")).unwrap();
+    Repository::init_bare(root.join("hidden/repo.git")).unwrap();
+    Repository::init_bare(dir.join("outside.git")).unwrap();
+    symlink(dir.join("outside.git"), root.join("ns/escape.git")).unwrap();
+    symlink(root.join("ns/repo.git"), root.join("ns/link.git")).unwrap();
+    symlink(root.join("ns/denied.git"), root.join("ns/bypass.git")).unwrap();
+    symlink(root.join("hidden/repo.git"), root.join("ns/unlisted.git")).unwrap();
+    symlink(root.join("ns/secret.git"), root.join("ns/open.git")).unwrap();
+
+    let repo_dir = RepoDir::with_config(Config {
+        repo_dir: root.to_str().unwrap().to_string(),
+        allowed: vec![String::from("ns/"), String::from("*")],
+        denied: vec![String::from("ns/denied.git")],
+        private: vec![String::from("ns/secret.git")],
+        ..Default::default()
+    });
+
+    let locate = |url: &str| {
+        let uri = URIReference::try_from(url).unwrap().into_owned();
+        let segments = request_segments(&Request::from_uri(uri).unwrap());
+        repo_dir.locate(&segments, None)
+    };
+
+    assert!(matches!(
+        locate("gemini://localhost/ns/repo.git"),
+        Ok(Location::Repo(..))
+    ));
+    assert!(matches!(
+        locate("gemini://localhost/ns/link.git/tree"),
+        Ok(Location::Repo(..))
+    ));
+    assert!(matches!(
+        locate("gemini://localhost/ns"),
+        Ok(Location::Group(_))
+    ));
+
+    assert_eq!(
+        locate("gemini://localhost/ns/open.git").err(),
+        Some(repo::Access::CertificateRequired)
+    );
+
+    let hostile = [
+        "gemini://localhost/ns/escape.git",
+        "gemini://localhost/ns/bypass.git",
+        "gemini://localhost/ns/unlisted.git",
+        "gemini://localhost/ns/%2e%2e/%2e%2e/outside.git",
+        "gemini://localhost/%2E%2E",
+        "gemini://localhost/ns%2f..%2f..%2foutside.git",
+        "gemini://localhost/ns/..%5c..%5coutside.git",
+        "gemini://localhost/ns/repo.git%00",
+        "gemini://localhost/ns/../../outside.git",
+        "gemini://localhost/%2Ftmp",
+    ];
+
+    for url in hostile.iter() {
+        assert_eq!(locate(url).err(), Some(repo::Access::NotFound), "{}", url);
+    }
+
+    let group = repo_dir.get_group("ns", None).unwrap();
+    assert_eq!(group.repos, vec!["link.git", "repo.git"]);
+    assert!(!is_safe_segment(".."));
+    assert!(!is_safe_segment("."));
+    assert!(is_safe_segment("..repo.git"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_discover_repos() {
+    let dir = env::temp_dir().join(format!("gemini-git-browser-discover-{}", process::id()));
+    let root = dir.join("repos");
+    fs::create_dir_all(root.join("deep/er")).unwrap();
+    let exported = Repository::init_bare(root.join("exported.git")).unwrap();
+    File::create(exported.path().join("git-daemon-export-ok")).unwrap();
+    let marked = Repository::init_bare(root.join("deep/er/marked.git")).unwrap();
+    marked
+        .config()
+        .unwrap()
+        .set_bool("gemini.export", true)
+        .unwrap();
+    let opted_out = Repository::init_bare(root.join("deep/opted-out.git")).unwrap();
+    File::create(opted_out.path().join("git-daemon-export-ok")).unwrap();
+    opted_out
+        .config()
+        .unwrap()
+        .set_bool("gemini.export", false)
+        .unwrap();
+    Repository::init_bare(root.join("deep/unmarked.git")).unwrap();
+    let work = Repository::init(root.join("work")).unwrap();
+    File::create(work.path().join("git-daemon-export-ok")).unwrap();
+    let inner = Repository::init_bare(root.join("exported.git/inner.git")).unwrap();
+    File::create(inner.path().join("git-daemon-export-ok")).unwrap();
+    symlink(root.join("deep/er"), root.join("link")).unwrap();
+
+    let repo_dir = RepoDir::with_config(Config {
+        repo_dir: root.to_str().unwrap().to_string(),
+        discover: true,
+        ..Default::default()
+    });
+
+    assert_eq!(
+        repo_dir.config.discovered,
+        vec!["deep/er/marked.git", "exported.git", "work"]
+    );
+    assert_eq!(
+        repo_dir.get_repos(None).unwrap(),
+        vec!["deep/er/marked.git", "exported.git", "work"]
+    );
+
+    let locate = |path: &str| {
+        let segments = path.split('/').map(String::from).collect::<Vec<String>>();
+        repo_dir.locate(&segments, None)
+    };
+
+    assert!(matches!(
+        locate("deep/er/marked.git/tree"),
+        Ok(Location::Repo(path, _)) if path == "deep/er/marked.git"
+    ));
+    assert!(matches!(locate("deep"), Ok(Location::Group(_))));
+    assert!(matches!(locate("work"), Ok(Location::Repo(..))));
+
+    // Repos aren't descended into, so this is a path within the outer one.
+    assert!(!repo_dir.config.check_dir("exported.git/inner.git"));
+    assert!(matches!(
+        locate("exported.git/inner.git"),
+        Ok(Location::Repo(path, rest)) if path == "exported.git" && rest == ["inner.git"]
+    ));
+
+    for path in ["deep/unmarked.git", "deep/opted-out.git", "link/marked.git"].iter() {
+        assert!(!repo_dir.config.check_dir(path), "{}", path);
+        assert_eq!(locate(path).err(), Some(repo::Access::NotFound), "{}", path);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_nested_groups() {
+    let dir = env::temp_dir().join(format!("gemini-git-browser-nested-{}", process::id()));
+    Repository::init_bare(dir.join("top.git")).unwrap();
+    Repository::init_bare(dir.join("org/team/sub/repo.git")).unwrap();
+    Repository::init_bare(dir.join("org/team/other.git")).unwrap();
+
+    let repo_dir = RepoDir::with_config(Config {
+        repo_dir: dir.to_str().unwrap().to_string(),
+        allowed: vec![String::from("top.git"), String::from("org/")],
+        ..Default::default()
+    });
+
+    let locate = |path: &str| {
+        let segments = path.split('/').map(String::from).collect::<Vec<String>>();
+        repo_dir.locate(&segments, None)
+    };
+
+    assert!(matches!(
+        locate("top.git"),
+        Ok(Location::Repo(path, rest)) if path == "top.git" && rest.is_empty()
+    ));
+    assert!(matches!(
+        locate("org/team/sub/repo.git/blob/abc/a.txt"),
+        Ok(Location::Repo(path, rest))
+            if path == "org/team/sub/repo.git" && rest == ["blob", "abc", "a.txt"]
+    ));
+    assert!(matches!(
+        locate("org/team"),
+        Ok(Location::Group(path)) if path == "org/team"
+    ));
+    assert_eq!(locate("org/missing").err(), Some(repo::Access::NotFound));
+
+    let group = repo_dir.get_group("", None).unwrap();
+    assert_eq!(group.groups, vec!["org"]);
+    assert_eq!(group.repos, vec!["top.git"]);
+    let group = repo_dir.get_group("org/team", None).unwrap();
+    assert_eq!(group.groups, vec!["sub"]);
+    assert_eq!(group.repos, vec!["other.git"]);
+    assert_eq!(
+        repo_dir.get_repos(None).unwrap(),
+        vec!["org/team/other.git", "org/team/sub/repo.git", "top.git"]
+    );
+
+    // The namespace page of a nested group links to both.
+    let shared = shared_repo_dir().join("ns/nested");
+    Repository::init_bare(shared.join("sub/deep.git")).unwrap();
+    Repository::init_bare(shared.join("top.git")).unwrap();
+    assert_eq!(
+        repo::Repo::new("ns/nested/sub/deep.git").unwrap().name,
+        "deep"
+    );
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let uri = URIReference::try_from("gemini://localhost/ns/nested")
+        .unwrap()
+        .into_owned();
+    let mut response = runtime
+        .block_on(actions::index(Request::from_uri(uri).unwrap()))
+        .unwrap();
+    let page = match response.take_body() {
+        Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+        _ => panic!("The namespace page has no body"),
+    };
+    assert!(page.contains("## Groups"), "{}", page);
+    assert!(page.contains("=> /ns/nested/sub 📁 sub"), "{}", page);
+    assert!(
+        page.contains("=> /ns/nested/top.git 📚 top.git"),
+        "{}",
+        page
+    );
+
+    fs::remove_dir_all(shared).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_non_bare_repo() {
+    let dir = shared_repo_dir().join("ns/work");
+    let git = Repository::init(&dir).unwrap();
+    let signature = signature();
+    fs::write(dir.join("a.txt"), "a").unwrap();
+    let mut index = git.index().unwrap();
+    index.add_path(Path::new("a.txt")).unwrap();
+    let tree = git.find_tree(index.write_tree().unwrap()).unwrap();
+    git.commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])
+        .unwrap();
+    let branch = git.head().unwrap().shorthand().unwrap().to_string();
+
+    fs::write(dir.join("a.txt"), "changed").unwrap();
+    fs::write(dir.join("b.txt"), "b").unwrap();
+    fs::write(dir.join("c.txt"), "c").unwrap();
+    index.add_path(Path::new("c.txt")).unwrap();
+    index.write().unwrap();
+
+    assert!(is_repo_dir(dir.to_str().unwrap()));
+    assert!(!is_repo_dir(dir.parent().unwrap().to_str().unwrap()));
+
+    let repo = repo::Repo::new("ns/work").unwrap();
+    let details = serde_json::to_value(repo.get_details().unwrap()).unwrap();
+    assert_eq!(details["active_branch"], branch);
+    assert_eq!(details["branches"], json!([branch]));
+    assert_eq!(details["is_bare"], false);
+    assert_eq!(
+        serde_json::to_value(repo.get_status().unwrap()).unwrap(),
+        json!([
+            {"path": "a.txt", "state": "modified"},
+            {"path": "b.txt", "state": "untracked"},
+            {"path": "c.txt", "state": "staged new file"}
+        ])
+    );
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |url: &str| {
+        let uri = URIReference::try_from(url).unwrap().into_owned();
+        let mut response = runtime
+            .block_on(actions::index(Request::from_uri(uri).unwrap()))
+            .unwrap();
+        let page = match response.take_body() {
+            Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+            _ => String::new(),
+        };
+
+        (response.header().status.code(), page)
+    };
+
+    let (status, page) = get("gemini://localhost/ns/work");
+    assert_eq!(status, 20);
+    assert!(
+        page.contains(&format!("=> /ns/work/branch/{}", branch)),
+        "{}",
+        page
+    );
+    assert!(page.contains("a.txt"), "{}", page);
+    // The status page is off unless `working_tree_status` is set.
+    assert_eq!(get("gemini://localhost/ns/work/status").0, 51);
+
+    let config = repo::repo_dir().config.clone();
+    repo::reload(RepoDir::with_config(Config {
+        working_tree_status: true,
+        ..config.clone()
+    }));
+    let (status, page) = get("gemini://localhost/ns/work/status");
+    let (_, repo_page) = get("gemini://localhost/ns/work");
+    repo::reload(RepoDir::with_config(config));
+
+    assert_eq!(status, 20);
+    assert!(page.contains("* modified: a.txt"), "{}", page);
+    assert!(page.contains("* untracked: b.txt"), "{}", page);
+    assert!(page.contains("* staged new file: c.txt"), "{}", page);
+    assert!(repo_page.contains("=> /ns/work/status"), "{}", repo_page);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_config_changes() {
+    let old = Config {
+        title: String::from("Old"),
+        allowed: vec![String::from("ritesh/")],
+        ..Default::default()
+    };
+    let new = Config {
+        title: String::from("New"),
+        port: 1966,
+        ..old.clone()
+    };
+
+    assert!(old.changes(&old.clone()).is_empty());
+    assert_eq!(
+        old.changes(&new),
+        vec![
+            "`port` changed from 1965 to 1966, restart the server to apply it",
+            "`title` changed from \"Old\" to \"New\"",
+        ]
+    );
+
+    let mut new = old.clone();
+    new.certificates
+        .insert(String::from("0123abcd"), String::from("ritesh"));
+    new.users
+        .insert(String::from("ritesh"), vec![String::from("ritesh/")]);
+    assert_eq!(
+        old.changes(&new),
+        vec!["`certificates` changed", "`users` changed"]
+    );
+}
+
+#[test]
+fn test_tree_context() {
+    assert_eq!(tree_context("master", None), "master");
+    assert_eq!(
+        tree_context("feature/x", vec!["src", "my dir", "50%?"]),
+        "feature/x/src/my%20dir/50%25%3F"
+    );
+}
+
+#[test]
+fn test_lru() {
+    let mut cache = Lru::new(2);
+
+    cache.insert("a", 1);
+    cache.insert("b", 2);
+    assert_eq!(cache.get(&"a"), Some(1));
+
+    cache.insert("c", 3);
+    assert_eq!(cache.get(&"b"), None);
+    assert_eq!(cache.get(&"a"), Some(1));
+    assert_eq!(cache.get(&"c"), Some(3));
+    assert_eq!((cache.len(), cache.hits, cache.misses), (2, 3, 1));
+
+    let mut disabled = Lru::new(0);
+    disabled.insert("a", 1);
+    assert!(disabled.is_empty());
+}
+
+/// Returns the repo dir used by `repo::Repo::new`, which is set up once per
+/// test run. Each test creates its own repos under `ns/`.
+fn shared_repo_dir() -> PathBuf {
+    static INIT: Once = Once::new();
+    let dir = env::temp_dir().join(format!("gemini-git-browser-repos-{}", process::id()));
+
+    INIT.call_once(|| {
+        fs::create_dir_all(dir.join("ns")).unwrap();
+        repo::init(Config {
+            repo_dir: dir.to_str().unwrap().to_string(),
+            allowed: vec![String::from("ns/")],
+            ..Default::default()
+        })
+        .unwrap();
+    });
+
+    dir
+}
+
+/// The author and committer of the commit made by `init_repo`.
+fn signature() -> Signature<'static> {
+    Signature::new("Author", "a@example.com", &Time::new(1_600_000_000, 120)).unwrap()
+}
+
+/// Creates a bare repo at `path` in the shared repo dir, with an "Initial"
+/// commit of `files` on `branch`, which HEAD points to.
+fn init_repo(path: &str, branch: &str, files: &[(&str, &[u8])]) -> (PathBuf, Repository, Oid) {
+    let dir = shared_repo_dir().join(path);
+    let git = Repository::init_bare(&dir).unwrap();
+    let reference = format!("refs/heads/{}", branch);
+    let commit = {
+        let mut tree = git.treebuilder(None).unwrap();
+        for (name, content) in files {
+            tree.insert(name, git.blob(content).unwrap(), 0o100644)
+                .unwrap();
+        }
+        let tree = git.find_tree(tree.write().unwrap()).unwrap();
+        git.commit(
+            Some(&reference),
+            &signature(),
+            &signature(),
+            "Initial",
+            &tree,
+            &[],
+        )
+        .unwrap()
+    };
+    git.set_head(&reference).unwrap();
+
+    (dir, git, commit)
+}
+
+#[test]
+fn test_init_once() {
+    let dir = shared_repo_dir();
+
+    assert!(repo::init(Config::default()).is_err());
+    assert_eq!(repo::repo_dir().config.repo_dir, dir.to_str().unwrap());
+}
+
+#[test]
+fn test_stream_blob() {
+    let dir = shared_repo_dir().join("ns/big.git");
+    let git = Repository::init_bare(&dir).unwrap();
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let hash = git.blob(&content).unwrap().to_string();
+
+    let repo = repo::Repo::new("ns/big.git").unwrap();
+    let mut chunks = Vec::new();
+    repo.stream_blob(&hash, 4096, |chunk| {
+        chunks.push(chunk);
+        true
+    })
+    .unwrap();
+
+    assert_eq!(repo.get_blob_size(&hash).unwrap(), content.len());
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 4096));
+    assert_eq!(chunks.concat(), content);
+
+    let mut sent = 0;
+    repo.stream_blob(&hash, 4096, |_| {
+        sent += 1;
+        sent < 3
+    })
+    .unwrap();
+    assert_eq!(sent, 3);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_stream_packed_blob() {
+    let dir = shared_repo_dir().join("ns/packed.git");
+    let git = Repository::init_bare(&dir).unwrap();
+    let mut seed = 1u32;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    };
+
+    // Larger than `max_blob_size`, so it can only be sent from the pack.
+    let large = random(1536 * 1024);
+    let small = random(10_000);
+    let mut changed = small.clone();
+    changed[5000] ^= 1;
+    let base = random(1536 * 1024);
+    let mut delta = base.clone();
+    delta[5000] ^= 1;
+
+    let blobs = [&large, &small, &changed, &base, &delta]
+        .iter()
+        .map(|content| git.blob(content).unwrap())
+        .collect::<Vec<_>>();
+
+    for group in [&blobs[..1], &blobs[1..3], &blobs[3..]].iter() {
+        let mut builder = git.packbuilder().unwrap();
+        let mut pack = git2::Buf::new();
+
+        for oid in group.iter() {
+            builder.insert_object(*oid, None).unwrap();
+        }
+
+        builder.write_buf(&mut pack).unwrap();
+        let odb = git.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        writer.write_all(&pack).unwrap();
+        writer.commit().unwrap();
+    }
+
+    for oid in blobs.iter() {
+        let hash = oid.to_string();
+        fs::remove_file(dir.join("objects").join(&hash[..2]).join(&hash[2..])).unwrap();
+    }
+
+    let repo = repo::Repo::new("ns/packed.git").unwrap();
+
+    for (oid, content) in blobs[..3].iter().zip([&large, &small, &changed].iter()) {
+        let hash = oid.to_string();
+        let mut chunks = Vec::new();
+        repo.stream_blob(&hash, 4096, |chunk| {
+            chunks.push(chunk);
+            true
+        })
+        .unwrap();
+
+        assert!(repo.can_stream_blob(&hash));
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 4096));
+        assert_eq!(&chunks.concat(), *content);
+    }
+
+    // One of two similar blobs packed together is stored as a delta, which
+    // would have to be read whole.
+    let streamed = blobs[3..]
+        .iter()
+        .map(|oid| {
+            let hash = oid.to_string();
+            let sent = repo.stream_blob(&hash, 4096, |_| true).is_ok();
+            assert_eq!(sent, repo.can_stream_blob(&hash));
+            sent
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(streamed.iter().filter(|sent| **sent).count(), 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_lfs_pointer() {
+    let oid = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+    let pointer = format!(
+        "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12345\n",
+        oid
+    );
+
+    assert_eq!(
+        LfsPointer::parse(pointer.as_bytes()),
+        Some(LfsPointer {
+            oid: String::from(oid),
+            size: 12345
+        })
+    );
+    assert_eq!(
+        LfsPointer::parse(b"version https://git-lfs.github.com/spec/v1\nsize 1\n"),
+        None
+    );
+    assert_eq!(
+        LfsPointer::parse(pointer.replace("sha256:4d", "sha256:../").as_bytes()),
+        None
+    );
+    assert_eq!(LfsPointer::parse(b"just a text file\n"), None);
+
+    let dir = shared_repo_dir().join("ns/lfs.git");
+    let git = Repository::init_bare(&dir).unwrap();
+    let hash = git.blob(pointer.as_bytes()).unwrap().to_string();
+    let store = dir.join("lfs/objects/4d/7a");
+    fs::create_dir_all(&store).unwrap();
+
+    let repo = repo::Repo::new("ns/lfs.git").unwrap();
+    let pointer = repo.get_lfs_pointer(&hash).unwrap();
+    assert_eq!(repo.get_lfs_object(&pointer), None);
+
+    fs::write(store.join(oid), vec![0; 12345]).unwrap();
+    assert_eq!(repo.get_lfs_object(&pointer), Some(store.join(oid)));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_commits_and_tags() {
+    let (dir, git, initial) = init_repo("ns/feed.git", "main", &[]);
+    let tree = git.find_commit(initial).unwrap().tree().unwrap();
+
+    let mut parents = vec![git.find_commit(initial).unwrap()];
+    for (i, time) in [1_600_000_000, 1_600_000_100, 1_600_000_200]
+        .iter()
+        .enumerate()
+    {
+        let signature = Signature::new("Author", "a@example.com", &Time::new(*time, 0)).unwrap();
+        let parent_refs = parents.iter().collect::<Vec<&Commit>>();
+        let message = format!("Commit {}\n\nBody {}", i, i);
+        let oid = git
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                &message,
+                &tree,
+                &parent_refs,
+            )
+            .unwrap();
+        parents = vec![git.find_commit(oid).unwrap()];
+    }
+
+    let head = parents[0].as_object().clone();
+    git.tag_lightweight("v1", &head, false).unwrap();
+    let tagger = Signature::new("Tagger", "t@example.com", &Time::new(1_700_000_000, 0)).unwrap();
+    git.tag("v2", &head, &tagger, "Release 2", false).unwrap();
+
+    let repo = repo::Repo::new("ns/feed.git").unwrap();
+
+    let commits = repo.get_commits("main", 2).unwrap();
+    assert_eq!(commits.len(), 2);
+    assert_eq!(commits[0].summary, "Commit 2");
+    assert_eq!(commits[0].message, "Commit 2\n\nBody 2");
+    assert_eq!(commits[0].time, "2020-09-13T12:30:00Z");
+    assert_eq!(commits[0].date, "2020-09-13");
+    assert_eq!(commits[1].summary, "Commit 1");
+    assert!(repo.get_commits("missing", 2).is_err());
+
+    let (branch, latest) = repo.get_latest_commit().unwrap().unwrap();
+    assert_eq!(branch, "main");
+    assert_eq!(latest.id, commits[0].id);
+
+    let tags = repo.get_tags().unwrap();
+    assert_eq!(
+        tags.iter()
+            .map(|tag| tag.name.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["v2", "v1"]
+    );
+    assert_eq!(tags[0].author, "Tagger");
+    assert_eq!(tags[0].message, "Release 2");
+    assert_eq!(tags[0].date, "2023-11-14");
+    assert_eq!(tags[1].message, "Commit 2\n\nBody 2");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_titan_upload() {
+    let (request, size) = titan::parse_request(
+        "titan://localhost/ns/edit.git/branch/main/a.txt;mime=text/plain;size=5;token=x",
+    )
+    .unwrap();
+    assert_eq!(size, 5);
+    assert_eq!(
+        request_segments(&request),
+        vec!["ns", "edit.git", "branch", "main", "a.txt"]
+    );
+    assert!(titan::parse_request("titan://localhost/ns/edit.git/branch/main/a.txt").is_err());
+    assert!(titan::parse_request("gemini://localhost/a.txt;size=5").is_err());
+    assert!(titan::parse_request("titan://localhost/a.txt;size=big").is_err());
+
+    let fingerprint = cert_fingerprint(b"certificate");
+    let mut config = Config {
+        editors: vec![String::from("ritesh")],
+        ..Default::default()
+    };
+    config
+        .certificates
+        .insert(fingerprint.clone(), String::from("ritesh"));
+    config
+        .users
+        .insert(String::from("ritesh"), vec![String::from("ns/")]);
+    assert_eq!(
+        config.check_editor("ns/edit.git", None),
+        Access::CertificateRequired
+    );
+    assert_eq!(
+        config.check_editor("ns/edit.git", Some(&fingerprint)),
+        Access::Allowed
+    );
+    assert_eq!(
+        config.check_editor("other/edit.git", Some(&fingerprint)),
+        Access::NotAuthorized
+    );
+    config.editors.clear();
+    assert_eq!(
+        config.check_editor("ns/edit.git", Some(&fingerprint)),
+        Access::NotAuthorized
+    );
+
+    let (dir, git, _) = init_repo("ns/edit.git", "main", &[]);
+
+    let repo = repo::Repo::new("ns/edit.git").unwrap();
+    let path = |path: &str| path.split('/').map(String::from).collect::<Vec<String>>();
+    let head = || {
+        git.find_reference("refs/heads/main")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+    };
+    let file_at = |path: &str| {
+        let commit = head();
+        let content = commit
+            .tree()
+            .unwrap()
+            .get_path(Path::new(path))
+            .ok()
+            .map(|entry| git.find_blob(entry.id()).unwrap().content().to_vec());
+        (commit.summary().unwrap().to_string(), content)
+    };
+
+    repo.commit_file(
+        "main",
+        &path("sub/dir/a.txt"),
+        Some(b"hello"),
+        "ritesh",
+        &fingerprint,
+    )
+    .unwrap();
+    assert_eq!(
+        file_at("sub/dir/a.txt"),
+        (
+            String::from("Update sub/dir/a.txt"),
+            Some(b"hello".to_vec())
+        )
+    );
+    assert_eq!(
+        repo.commit_file(
+            "main",
+            &path("sub/dir/a.txt"),
+            Some(b"hello"),
+            "ritesh",
+            &fingerprint
+        )
+        .unwrap_err()
+        .to_string(),
+        "The file is unchanged"
+    );
+    assert!(repo
+        .commit_file(
+            "main",
+            &path("sub/dir"),
+            Some(b"hello"),
+            "ritesh",
+            &fingerprint
+        )
+        .is_err());
+    assert!(repo
+        .commit_file(
+            "main",
+            &path("sub/dir/a.txt/b.txt"),
+            Some(b"hello"),
+            "ritesh",
+            &fingerprint
+        )
+        .is_err());
+    assert!(repo
+        .commit_file(
+            "missing",
+            &path("a.txt"),
+            Some(b"hello"),
+            "ritesh",
+            &fingerprint
+        )
+        .is_err());
+
+    repo.commit_file("main", &path("sub/dir/a.txt"), None, "ritesh", &fingerprint)
+        .unwrap();
+    assert_eq!(file_at("sub"), (String::from("Delete sub/dir/a.txt"), None));
+
+    let author = head().author().to_owned();
+    assert_eq!(author.name(), Some("ritesh"));
+    assert_eq!(author.email(), Some(fingerprint.as_str()));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_api() {
+    let (dir, git, commit) = init_repo("ns/api.git", "main", &[("a.txt", b"a")]);
+    let tree = git.find_commit(commit).unwrap().tree().unwrap();
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |url: &str| {
+        let uri = URIReference::try_from(url).unwrap().into_owned();
+        let mut response = runtime
+            .block_on(actions::index(Request::from_uri(uri).unwrap()))
+            .unwrap();
+        let header = response.header();
+        let header = (header.status.code(), header.meta.as_str().to_string());
+
+        match response.take_body() {
+            Some(Body::Bytes(bytes)) => (header, serde_json::from_slice::<Value>(&bytes).ok()),
+            _ => (header, None),
+        }
+    };
+    let json_header = (20, String::from("application/json"));
+    assert_eq!(
+        get("gemini://localhost/ns?format=jsonx").0 .1,
+        "text/gemini"
+    );
+
+    let (header, json) = get("gemini://localhost/ns?format=json");
+    assert_eq!(header, json_header);
+    assert!(json.unwrap()["repos"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("api.git")));
+
+    let (header, json) = get("gemini://localhost/ns/api.git?x=1&format=json");
+    let json = json.unwrap();
+    assert_eq!(header, json_header);
+    assert_eq!(json["details"]["active_branch"], "main");
+    assert_eq!(json["details"]["branches"], json!(["main"]));
+    assert_eq!(json["blobs"][0]["name"], "a.txt");
+
+    let (header, json) = get("gemini://localhost/ns/api.git/log/main?format=json&x=1");
+    let json = json.unwrap();
+    assert_eq!(header, json_header);
+    assert_eq!(json["commits"][0]["summary"], "Initial");
+    assert_eq!(json["commits"][0]["tree"], tree.id().to_string());
+
+    assert_eq!(
+        get("gemini://localhost/ns/api.git/log/missing?format=json")
+            .0
+             .0,
+        51
+    );
+    assert_eq!(get("gemini://localhost/other?format=json").0 .0, 51);
+
+    let blob = tree.get_name("a.txt").unwrap().id();
+    for url in [
+        String::from("gemini://localhost/ns/api.git/branch/missing"),
+        String::from("gemini://localhost/ns/api.git/tree/nothex"),
+        format!("gemini://localhost/ns/api.git/tree/{}", blob),
+    ]
+    .iter()
+    {
+        assert_eq!(get(url).0 .0, 51, "{}", url);
+    }
+
+    // An empty repo has no HEAD to show yet.
+    let empty = shared_repo_dir().join("ns/empty.git");
+    Repository::init_bare(&empty).unwrap();
+    assert_eq!(get("gemini://localhost/ns/empty.git").0 .0, 51);
+    assert_eq!(get("gemini://localhost/ns?format=json").0 .0, 20);
+    fs::remove_dir_all(empty).unwrap();
+
+    let (header, json) = get(&format!(
+        "gemini://localhost/ns/api.git/blob/{}/a.txt?format=json",
+        blob
+    ));
+    assert_eq!(header, json_header);
+    assert_eq!(
+        json.unwrap(),
+        json!({
+            "path": "ns/api.git",
+            "hash": blob.to_string(),
+            "name": "a.txt",
+            "size": 1,
+            "too_large": false,
+            "lfs": null
+        })
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_gemtext_to_html() {
+    let gemtext = "# Title <1>\r\n\r\n=> /ns/repo.git 📚 repo\r\n=> gemini://example.com\r\n* one\r\n* two & three\r\ntext\r\n```alt\r\n<pre> * kept\r\n```\r\n> quote\r\n### Small";
+
+    assert_eq!(
+        gemtext_to_html(gemtext),
+        "<h1>Title &lt;1&gt;</h1>\n\
+         <p><a href=\"/ns/repo.git\">📚 repo</a></p>\n\
+         <p><a href=\"gemini://example.com\">gemini://example.com</a></p>\n\
+         <ul>\n<li>one</li>\n<li>two &amp; three</li>\n</ul>\n\
+         <p>text</p>\n\
+         <pre>\n&lt;pre&gt; * kept\n</pre>\n\
+         <blockquote>quote</blockquote>\n\
+         <h3>Small</h3>\n"
+    );
+    assert_eq!(
+        gemtext_to_html("* open\r\n```\r\ncode"),
+        "<ul>\n<li>open</li>\n</ul>\n<pre>\ncode\n</pre>\n"
+    );
+    assert_eq!(
+        gemtext_to_html(
+            "=> javascript:alert(1) click\r\n=> JavaScript:alert(1)\r\n\
+             => data:text/html,<script>alert(1)</script> data\r\n\
+             => HTTPS://example.com/a:b web\r\n=> ../a:b.txt file\r\n=> mailto:a@example.com"
+        ),
+        "<p>click</p>\n\
+         <p>JavaScript:alert(1)</p>\n\
+         <p>data</p>\n\
+         <p><a href=\"HTTPS://example.com/a:b\">web</a></p>\n\
+         <p><a href=\"../a:b.txt\">file</a></p>\n\
+         <p><a href=\"mailto:a@example.com\">mailto:a@example.com</a></p>\n"
+    );
+}
+
+#[test]
+fn test_spartan_request() {
+    assert_eq!(
+        spartan::parse_request("localhost /ns/repo.git 0").unwrap(),
+        (String::from("localhost"), String::from("/ns/repo.git"), 0)
+    );
+    assert_eq!(spartan::parse_request("localhost / 12").unwrap().2, 12);
+    assert!(spartan::parse_request("localhost ns 0").is_err());
+    assert!(spartan::parse_request("localhost / x").is_err());
+    assert!(spartan::parse_request("localhost / 0 extra").is_err());
+    assert!(spartan::parse_request("gemini://localhost/").is_err());
+
+    assert_eq!(
+        spartan::redirect_path("gemini://localhost:1966/ns/repo.git/log/main"),
+        "/ns/repo.git/log/main"
+    );
+    assert_eq!(spartan::redirect_path("gemini://localhost/a?b"), "/a?b");
+    assert_eq!(spartan::redirect_path("/ns"), "/ns");
+
+    let mut config = Config {
+        bind: vec![String::from("::1")],
+        spartan: true,
+        spartan_port: 1965,
+        ..Default::default()
+    };
+    assert!(config
+        .validate()
+        .unwrap_err()
+        .to_string()
+        .contains("spartan_port must differ from port"));
+
+    config.spartan_port = 300;
+    assert_eq!(config.spartan_addrs().unwrap()[0].to_string(), "[::1]:300");
+}
+
+#[test]
+fn test_write_archive() {
+    let (dir, _, _) = init_repo("ns/archive.git", "main", &[("a.txt", b"a")]);
+
+    let repo = repo::Repo::new("ns/archive.git").unwrap();
+    assert!(repo.get_archive("missing", ArchiveFormat::TarGz).is_err());
+
+    let mut tar_gz = vec![];
+    let archive = repo.get_archive("main", ArchiveFormat::TarGz).unwrap();
+    repo.write_archive(&archive, &mut tar_gz).unwrap();
+
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(tar_gz.as_slice()));
+    let entries = tar
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+
+            (
+                entry.path().unwrap().to_str().unwrap().to_string(),
+                entry.header().mtime().unwrap(),
+                content,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        vec![
+            (String::from("archive-main/"), 1_600_000_000, String::new()),
+            (
+                String::from("archive-main/a.txt"),
+                1_600_000_000,
+                String::from("a")
+            ),
+        ]
+    );
+
+    let mut zip = vec![];
+    let archive = repo.get_archive("main", ArchiveFormat::Zip).unwrap();
+    repo.write_archive(&archive, &mut zip).unwrap();
+
+    let mut zip = zip::ZipArchive::new(io::Cursor::new(zip)).unwrap();
+    let mut content = String::new();
+    zip.by_name("archive-main/a.txt")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "a");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_clone_endpoints() {
+    let (dir, git, commit) = init_repo("ns/clone.git", "main", &[("a.txt", b"a")]);
+    let blob = git.blob(b"a").unwrap();
+    let signature = signature();
+    let v1 = git
+        .tag(
+            "v1",
+            &git.find_object(commit, None).unwrap(),
+            &signature,
+            "v1",
+            false,
+        )
+        .unwrap();
+    let v2 = git
+        .tag(
+            "v2",
+            &git.find_object(v1, None).unwrap(),
+            &signature,
+            "v2",
+            false,
+        )
+        .unwrap();
+
+    let repo = repo::Repo::new("ns/clone.git").unwrap();
+    assert_eq!(
+        repo.get_info_refs().unwrap(),
+        format!(
+            "{c}\trefs/heads/main\n{v1}\trefs/tags/v1\n{c}\trefs/tags/v1^{{}}\n\
+             {v2}\trefs/tags/v2\n{c}\trefs/tags/v2^{{}}\n",
+            c = commit,
+            v1 = v1,
+            v2 = v2
+        )
+    );
+
+    let loose_object = |hash: &str| {
+        let mut object = vec![];
+        repo.write_loose_object(hash, &mut object).unwrap();
+        let mut content = vec![];
+        flate2::read::ZlibDecoder::new(object.as_slice())
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    };
+    assert_eq!(loose_object(&blob.to_string()), b"blob 1\0a");
+
+    let missing = "0000000000000000000000000000000000000000";
+    assert!(!repo.has_object(missing));
+    assert!(repo.write_loose_object(missing, io::sink()).is_err());
+
+    // Packed objects stored whole are streamed from the pack too.
+    let mut builder = git.packbuilder().unwrap();
+    let mut pack = git2::Buf::new();
+    builder.insert_commit(commit).unwrap();
+    builder.write_buf(&mut pack).unwrap();
+    let odb = git.odb().unwrap();
+    let mut writer = odb.packwriter().unwrap();
+    writer.write_all(&pack).unwrap();
+    writer.commit().unwrap();
+
+    let data = odb.read(commit).unwrap().data().to_vec();
+    let hash = commit.to_string();
+    fs::remove_file(dir.join("objects").join(&hash[..2]).join(&hash[2..])).unwrap();
+    assert!(repo.has_object(&hash));
+    assert!(repo.can_stream_object(&hash));
+    assert_eq!(
+        loose_object(&hash),
+        [format!("commit {}\0", data.len()).as_bytes(), &data].concat()
+    );
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |path: &str| {
+        let url = format!("gemini://localhost/ns/clone.git/clone/{}", path);
+        let uri = URIReference::try_from(url.as_str()).unwrap().into_owned();
+        let mut response = runtime
+            .block_on(actions::index(Request::from_uri(uri).unwrap()))
+            .unwrap();
+        let mut body = vec![];
+
+        if let Some(Body::Reader(mut reader)) = response.take_body() {
+            runtime
+                .block_on(tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut body))
+                .unwrap();
+        }
+
+        (response.header().status.code(), body)
+    };
+
+    let pack_name = fs::read_dir(dir.join("objects/pack"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .find(|name| name.ends_with(".pack"))
+        .unwrap();
+    assert_eq!(
+        get(&format!("objects/pack/{}", pack_name)),
+        (
+            20,
+            fs::read(dir.join("objects/pack").join(&pack_name)).unwrap()
+        )
+    );
+    assert!(repo.get_pack_path("../config").is_err());
+    assert_eq!(get(&format!("objects/{}/{}", &hash[..2], &hash[2..])).0, 20);
+    assert_eq!(get(&format!("objects/00/{}", &missing[2..])).0, 51);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_gemtext_not_escaped() {
+    let (dir, git, initial) = init_repo("ns/escape.git", "main", &[]);
+    let initial = git.find_commit(initial).unwrap();
+    git.commit(
+        Some("refs/heads/main"),
+        &signature(),
+        &signature(),
+        "Don't use <b> & \"quotes\"",
+        &initial.tree().unwrap(),
+        &[&initial],
+    )
+    .unwrap();
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let uri = URIReference::try_from("gemini://localhost/ns/escape.git/log/main")
+        .unwrap()
+        .into_owned();
+    let mut response = runtime
+        .block_on(actions::index(Request::from_uri(uri).unwrap()))
+        .unwrap();
+    let page = match response.take_body() {
+        Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+        _ => panic!("The log page has no body"),
+    };
+    assert!(page.contains("Don't use <b> & \"quotes\""), "{}", page);
+
+    let html = actions::html_page(&page).unwrap();
+    assert!(html.contains("Don&#39;t use &lt;b&gt; &amp; &quot;quotes&quot;"));
+    assert!(!html.contains("&amp;#"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_template_dir_override() {
+    let dir = env::temp_dir().join(format!("gemini-git-browser-templates-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("footer.hbs"), "=> / {{ title }} home\n").unwrap();
+    let page = json!({
+        "title": "Test",
+        "heading": "ns",
+        "breadcrumbs": [],
+        "ns": "ns",
+        "groups": [],
+        "repos": ["a.git"]
+    });
+
+    let registry = actions::compile_templates(&Config {
+        templates: dir.to_str().unwrap().to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    let overridden = registry.gemtext.render("ns", &page).unwrap();
+    let lines: Vec<_> = overridden.lines().collect();
+    assert_eq!(lines.last(), Some(&"=> / Test home"), "{}", overridden);
+    // Templates missing from the dir fall back to the built-in ones.
+    assert_eq!(lines.first(), Some(&"# ns"), "{}", overridden);
+    assert!(
+        overridden.contains("=> /ns/a.git 📚 a.git"),
+        "{}",
+        overridden
+    );
+
+    let built_in = actions::compile_templates(&Config::default())
+        .unwrap()
+        .gemtext
+        .render("ns", &page)
+        .unwrap();
+    assert_eq!(
+        built_in.lines().last(),
+        Some("=> / 🏠 Test Gemini Git Browser")
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_template_strict_mode() {
+    let registry = actions::compile_templates(&Config::default()).unwrap();
+    let page = json!({
+        "title": "Test",
+        "heading": "ns",
+        "breadcrumbs": [],
+        "ns": "ns",
+        "groups": []
+    });
+    assert!(registry.gemtext.render("ns", &page).is_err());
+    assert!(registry.markup.render("ns", &page).is_err());
+
+    let dir = env::temp_dir().join(format!("gemini-git-browser-strict-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ns.hbs"), "{{#each repos}}\n").unwrap();
+    let compiled = actions::compile_templates(&Config {
+        templates: dir.to_str().unwrap().to_string(),
+        ..Default::default()
+    });
+    assert!(compiled.is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_rendered_breadcrumbs() {
+    let (dir, git, initial) = init_repo("ns/crumbs.git", "main", &[]);
+    let mut bin = git.treebuilder(None).unwrap();
+    bin.insert("main.rs", git.blob(b"fn main() {}").unwrap(), 0o100644)
+        .unwrap();
+    let bin = bin.write().unwrap();
+    let mut src = git.treebuilder(None).unwrap();
+    src.insert("bin", bin, 0o040000).unwrap();
+    let src = src.write().unwrap();
+    let mut root = git.treebuilder(None).unwrap();
+    root.insert("src", src, 0o040000).unwrap();
+    let root = git.find_tree(root.write().unwrap()).unwrap();
+    git.commit(
+        Some("refs/heads/main"),
+        &signature(),
+        &signature(),
+        "Add src",
+        &root,
+        &[&git.find_commit(initial).unwrap()],
+    )
+    .unwrap();
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let url = format!("gemini://localhost/ns/crumbs.git/tree/{}/main/src/bin", bin);
+    let uri = URIReference::try_from(url.as_str()).unwrap().into_owned();
+    let mut response = runtime
+        .block_on(actions::index(Request::from_uri(uri).unwrap()))
+        .unwrap();
+    let page = match response.take_body() {
+        Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+        _ => panic!("The tree page has no body"),
+    };
+
+    // The heading, then a link up for every crumb but the current page.
+    let src_link = format!("=> /ns/crumbs.git/tree/{}/main/src 📁 src", src);
+    assert_eq!(
+        page.lines().take(7).collect::<Vec<&str>>(),
+        vec![
+            "# ns/crumbs.git - Gemini Git Browser",
+            "",
+            "=> / 🏠 Index",
+            "=> /ns 📁 ns",
+            "=> /ns/crumbs.git 📚 crumbs.git",
+            "=> /ns/crumbs.git/branch/main 🎋 main",
+            src_link.as_str(),
+        ],
+        "{}",
+        page
+    );
+
+    assert!(!page.contains("📁 bin"), "{}", page);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_rendered_atom_feed() {
+    let (dir, git, initial) = init_repo("ns/atom.git", "main", &[]);
+    let initial = git.find_commit(initial).unwrap();
+    let signature =
+        Signature::new("Author", "a@example.com", &Time::new(1_600_000_100, 0)).unwrap();
+    let commit = git
+        .commit(
+            Some("refs/heads/main"),
+            &signature,
+            &signature,
+            "Fix <b> & \"x\"\n\nBody",
+            &initial.tree().unwrap(),
+            &[&initial],
+        )
+        .unwrap();
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let uri = URIReference::try_from("gemini://localhost/ns/atom.git/atom/main")
+        .unwrap()
+        .into_owned();
+    let mut response = runtime
+        .block_on(actions::index(Request::from_uri(uri).unwrap()))
+        .unwrap();
+    assert_eq!(response.header().meta.as_str(), "application/atom+xml");
+    let feed = match response.take_body() {
+        Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+        _ => panic!("The feed has no body"),
+    };
+
+    let tree = initial.tree_id();
+    for line in [
+        String::from("<feed xmlns=\"http://www.w3.org/2005/Atom\">"),
+        String::from("  <id>gemini://localhost/ns/atom.git/atom/main</id>"),
+        String::from("  <updated>2020-09-13T12:28:20Z</updated>"),
+        String::from("  <link href=\"gemini://localhost/ns/atom.git/branch/main\"/>"),
+        format!(
+            "    <id>gemini://localhost/ns/atom.git/commit/{}</id>",
+            commit
+        ),
+        String::from("    <title>Fix &lt;b&gt; &amp; &quot;x&quot;</title>"),
+        String::from("    <updated>2020-09-13T12:28:20Z</updated>"),
+        format!(
+            "    <link href=\"gemini://localhost/ns/atom.git/tree/{}\"/>",
+            tree
+        ),
+        String::from("    <title>Initial</title>"),
+        String::from("    <updated>2020-09-13T12:26:40Z</updated>"),
+    ]
+    .iter()
+    {
+        assert!(feed.lines().any(|l| l == line), "{} not in {}", line, feed);
+    }
+    assert_eq!(feed.matches("<entry>").count(), 2);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_http_listener() {
+    shared_repo_dir();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let head = |text: &str| runtime.block_on(http::read_head(&mut text.as_bytes()));
+
+    assert_eq!(
+        head("GET /ns/a.git HTTP/1.1\r\nhost: example.com:8080\r\nAccept: */*\r\n\r\n"),
+        Some(http::RequestHead {
+            method: String::from("GET"),
+            target: String::from("/ns/a.git"),
+            host: Some(String::from("example.com:8080")),
+        })
+    );
+    assert_eq!(head("HEAD / HTTP/1.0\n\n").unwrap().host, None);
+    assert_eq!(head("GET / HTTP/2\r\n\r\n"), None);
+    assert_eq!(head("GET / HTTP/1.1 extra\r\n\r\n"), None);
+    assert_eq!(head("GET /\r\n\r\n"), None);
+    assert_eq!(head("GET / HTTP/1.1\r\nHost: example.com\r\n"), None);
+
+    let request = |host: Option<&str>, target: &str| {
+        http::gemini_request(&http::RequestHead {
+            method: String::from("GET"),
+            target: String::from(target),
+            host: host.map(String::from),
+        })
+        .map(|request| request.uri().to_string())
+    };
+    assert_eq!(
+        request(Some("example.com:8080"), "/ns/a.git").unwrap(),
+        "gemini://example.com/ns/a.git"
+    );
+    assert_eq!(request(None, "/").unwrap(), "gemini://localhost/");
+    assert_eq!(request(Some("[::1]:80"), "/").unwrap(), "gemini://[::1]/");
+    assert_eq!(request(Some("bad host"), "/"), None);
+
+    assert_eq!(http::http_status(20), (200, "OK"));
+    assert_eq!(http::http_status(31), (301, "Moved Permanently"));
+    assert_eq!(http::http_status(44), (429, "Too Many Requests"));
+    assert_eq!(http::http_status(51), (404, "Not Found"));
+    assert_eq!(http::http_status(60), (403, "Forbidden"));
+    assert_eq!(http::http_status(50), (500, "Internal Server Error"));
+    assert_eq!(http::http_status(42), (500, "Internal Server Error"));
+
+    actions::load_templates(&Config::default()).unwrap();
+    let send = |response: Response, head_only: bool| {
+        let mut out = vec![];
+        runtime
+            .block_on(http::send_response(&mut out, response, head_only))
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let page = send(Response::success_gemini("# Title\r\n"), false);
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"));
+    assert!(page.contains("<h1>Title</h1>"));
+
+    let page = send(Response::success_gemini("# Title\r\n"), true);
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(page.ends_with("\r\n\r\n"));
+    assert!(!page.contains("Title"));
+
+    // Pages larger than `max_blob_size` are sent as gemtext, not converted.
+    let large = "# Title\r\n".repeat(repo::repo_dir().config.max_blob_size / 9 + 1);
+    let reader = Box::new(io::Cursor::new(large.clone().into_bytes()));
+    let page = send(Response::success_gemini(Body::Reader(reader)), false);
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/gemini"));
+    assert!(page.ends_with(&large));
+
+    let page = send(Response::not_found(), false);
+    assert!(page.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn test_listeners() {
+    shared_repo_dir();
+    actions::load_templates(&Config::default()).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let exchange = |addr: std::net::SocketAddr, request: &str| {
+        runtime.block_on(async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut stream = loop {
+                match tokio::net::TcpStream::connect(addr).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        })
+    };
+    let free_addr = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let timeout = std::time::Duration::from_secs(5);
+
+    let addr = free_addr();
+    runtime.spawn(http::serve(addr, timeout, timeout));
+    let response = exchange(addr, "GET /robots.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("User-Agent: *"), "{}", response);
+    let response = exchange(addr, "POST / HTTP/1.1\r\n\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{}",
+        response
+    );
+
+    let addr = free_addr();
+    runtime.spawn(spartan::serve(addr, timeout, timeout));
+    let response = exchange(addr, "localhost /robots.txt 0\r\n");
+    assert!(response.starts_with("2 "), "{}", response);
+    assert!(response.contains("User-Agent: *"), "{}", response);
+    assert_eq!(
+        exchange(addr, "localhost ns 0\r\n"),
+        "4 Not a valid request\r\n"
+    );
+}
+
+#[test]
+fn test_repo_page_links() {
+    let (dir, git, commit) = init_repo("ns/links.git", "fix#1%", &[]);
+    git.branch("feature/x", &git.find_commit(commit).unwrap(), false)
+        .unwrap();
+
+    actions::load_templates(&Config::default()).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let uri = URIReference::try_from("gemini://localhost/ns/links.git")
+        .unwrap()
+        .into_owned();
+    let mut response = runtime
+        .block_on(actions::index(Request::from_uri(uri).unwrap()))
+        .unwrap();
+    let page = match response.take_body() {
+        Some(Body::Bytes(bytes)) => String::from_utf8(bytes).unwrap(),
+        _ => panic!("The repo page has no body"),
+    };
+
+    for link in [
+        "=> /ns/links.git/branch/fix%231%25 🎋 fix#1%",
+        "=> /ns/links.git/branch/feature/x 🎋 feature/x",
+        "=> /ns/links.git/archive/fix%231%25.tar.gz 📦 fix#1%.tar.gz",
+        "=> /ns/links.git/archive/fix%231%25.zip 📦 fix#1%.zip",
+        "=> /ns/links.git/log/fix%231%25 📜 fix#1% commits",
+        "=> /ns/links.git/atom/fix%231%25 📰 fix#1% commits",
+    ]
+    .iter()
+    {
+        assert!(page.contains(link), "{} not in {}", link, page);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
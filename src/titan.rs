@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Context};
+use log::error;
+use northstar::{uri::URIReference, Meta, Request, Response, ResponseHeader, Status};
+use rustls::{
+    internal::pemfile, Certificate, ClientCertVerified, ClientCertVerifier, DistinguishedNames,
+    ServerConfig, Session, TLSError,
+};
+use std::{convert::TryFrom, fs::File, io::BufReader, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    actions::{git_task, request_segments},
+    repo::{self, Location},
+    util,
+};
+
+/// Longest request line accepted, including the upload parameters.
+const REQUEST_MAX_LEN: usize = 2048;
+
+/// Accepts any client certificate, as Gemini clients use self-signed ones.
+/// The handshake signatures are still checked by the default methods, so a
+/// client has to hold the key of the certificate it presents.
+struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(vec![])
+    }
+
+    fn verify_client_cert(
+        &self,
+        _certs: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+fn tls_config(cert: &str, key: &str) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = File::open(cert)
+        .map_err(|_| ())
+        .and_then(|file| pemfile::certs(&mut BufReader::new(file)))
+        .map_err(|_| anyhow!("Failed to load TLS certificate `{}`", cert))?;
+
+    let mut keys = File::open(key)
+        .map_err(|_| ())
+        .and_then(|file| pemfile::pkcs8_private_keys(&mut BufReader::new(file)))
+        .ok()
+        .filter(|keys| !keys.is_empty())
+        .ok_or_else(|| anyhow!("Failed to load TLS key `{}`", key))?;
+
+    let mut config = ServerConfig::new(Arc::new(AnyClientCert));
+    config
+        .set_single_cert(certs, keys.swap_remove(0))
+        .context("Failed to use the TLS certificate")?;
+
+    Ok(Arc::new(config))
+}
+
+/// Accepts Titan uploads on `addr`, committing each to the branch and file
+/// named by its path. Gemini is served by northstar, which doesn't read
+/// anything after the request line, so Titan has its own listener.
+pub async fn serve(
+    addr: SocketAddr,
+    cert: String,
+    key: String,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let acceptor = TlsAcceptor::from(tls_config(&cert, &key)?);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+
+    loop {
+        let (stream, _) = listener.accept().await.context("Failed to accept client")?;
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, acceptor, timeout).await {
+                error!("Titan: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let stream = time::timeout(timeout, acceptor.accept(stream))
+        .await
+        .context("Client timed out during the TLS handshake")?
+        .context("Failed to establish TLS session")?;
+
+    let fingerprint = stream
+        .get_ref()
+        .1
+        .get_peer_certificates()
+        .and_then(|certs| certs.first().map(|cert| util::cert_fingerprint(&cert.0)));
+
+    let mut stream = BufStream::new(stream);
+
+    let response = match time::timeout(timeout, receive_upload(&mut stream)).await {
+        // Unlike pages, the commit isn't cut off by a deadline, so the client
+        // always learns whether it landed.
+        Ok(Ok((request, content))) => {
+            git_task(move || commit_upload(&request, &content, fingerprint.as_deref()))
+                .await
+                .unwrap_or_else(|err| {
+                    error!("Titan: {:?}", err);
+                    Response::new(ResponseHeader {
+                        status: Status::PERMANENT_FAILURE,
+                        meta: Meta::new_lossy("Failed to commit the upload"),
+                    })
+                })
+        }
+        Ok(Err(response)) => response,
+        Err(_) => Response::bad_request_lossy("Timed out receiving the upload"),
+    };
+
+    let header = response.header();
+    let header = format!("{} {}\r\n", header.status.code(), header.meta.as_str());
+
+    stream
+        .write_all(header.as_bytes())
+        .await
+        .context("Failed to send response")?;
+    stream.flush().await.context("Failed to send response")?;
+    stream.shutdown().await.ok();
+
+    Ok(())
+}
+
+/// Reads a Titan request line and the content following it, or returns the
+/// response refusing it.
+async fn receive_upload(
+    stream: &mut (impl AsyncBufRead + Unpin),
+) -> Result<(Request, Vec<u8>), Response> {
+    let mut line = vec![];
+
+    (&mut *stream)
+        .take(REQUEST_MAX_LEN as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|_| Response::bad_request_lossy("Failed to read the request"))?;
+
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| Response::bad_request_lossy("Request line is too long or not UTF-8"))?;
+
+    let (request, size) = parse_request(line).map_err(Response::bad_request_lossy)?;
+
+    let max_size = repo::repo_dir().config.max_blob_size;
+    if size > max_size {
+        return Err(Response::bad_request_lossy(format!(
+            "Uploads are limited to {} bytes",
+            max_size
+        )));
+    }
+
+    let mut content = vec![0; size];
+    stream
+        .read_exact(&mut content)
+        .await
+        .map_err(|_| Response::bad_request_lossy("Upload is shorter than its size"))?;
+
+    Ok((request, content))
+}
+
+/// Parses a request line, `titan://host/path;mime=...;size=...;token=...`,
+/// into the request for its URL and the size of the upload. The MIME type and
+/// token aren't used.
+pub(crate) fn parse_request(line: &str) -> Result<(Request, usize), String> {
+    let (url, params) = line
+        .split_once(';')
+        .ok_or("Upload parameters are missing")?;
+
+    let uri = URIReference::try_from(url)
+        .map_err(|_| String::from("Not a valid URL"))?
+        .into_owned();
+
+    if uri.scheme().map(|scheme| scheme.as_str()) != Some("titan") {
+        return Err(String::from("Only titan:// URLs are accepted"));
+    }
+
+    let mut size = None;
+
+    for param in params.split(';') {
+        match param.split_once('=') {
+            Some(("size", value)) => size = value.parse::<usize>().ok(),
+            Some(_) => {}
+            None => return Err(format!("Parameter `{}` has no value", param)),
+        }
+    }
+
+    let size = size.ok_or("Upload size is missing or not a number")?;
+    let request = Request::from_uri(uri).map_err(|err| err.to_string())?;
+
+    Ok((request, size))
+}
+
+/// Commits an upload to the file at `<repo>/branch/<branch>/<path>`, if the
+/// client's certificate belongs to an editor of the repo, and redirects to the
+/// branch's log. Uploads without content delete the file.
+pub(crate) fn commit_upload(
+    request: &Request,
+    content: &[u8],
+    fingerprint: Option<&str>,
+) -> anyhow::Result<Response> {
+    let repo_dir = repo::repo_dir();
+    let segments = request_segments(request);
+
+    let (path, rest) = match repo_dir.locate(&segments, fingerprint) {
+        Ok(Location::Repo(path, rest)) => (path, rest),
+        Ok(Location::Group(_)) | Err(repo::Access::NotFound) => return Ok(Response::not_found()),
+        Err(repo::Access::CertificateRequired) => {
+            return Ok(Response::client_certificate_required())
+        }
+        Err(repo::Access::NotAuthorized) => return Ok(Response::certificate_not_authorized()),
+    };
+
+    match repo_dir.config.check_editor(&path, fingerprint) {
+        util::Access::Allowed => {}
+        util::Access::CertificateRequired => return Ok(Response::client_certificate_required()),
+        util::Access::NotAuthorized => return Ok(Response::certificate_not_authorized()),
+    }
+
+    if rest.first().map(|s| s.as_str()) != Some("branch") {
+        return Ok(Response::not_found());
+    }
+
+    let repo = repo::Repo::new(&path).map_err(|err| anyhow!("{}", err))?;
+
+    let (branch, file) = match repo.split_branch(&rest[1..]) {
+        Some(split) => split,
+        None => return Ok(Response::not_found()),
+    };
+
+    let valid_path = !file.is_empty()
+        && file
+            .iter()
+            .all(|name| util::is_safe_segment(name) && !name.eq_ignore_ascii_case(".git"));
+
+    if !valid_path {
+        return Ok(Response::bad_request_lossy("Not a valid file path"));
+    }
+
+    // check_editor only allows certificates mapped to a user.
+    let fingerprint = fingerprint.unwrap_or_default();
+    let author = repo_dir
+        .config
+        .cert_user(fingerprint)
+        .cloned()
+        .unwrap_or_default();
+    let content = Some(content).filter(|content| !content.is_empty());
+
+    if let Err(err) = repo.commit_file(&branch, &file, content, &author, fingerprint) {
+        return Ok(Response::bad_request_lossy(err.to_string()));
+    }
+
+    let host = request
+        .uri()
+        .host()
+        .map(|host| host.to_string())
+        .unwrap_or_default();
+    let port = match repo_dir.config.port {
+        northstar::GEMINI_PORT => String::new(),
+        port => format!(":{}", port),
+    };
+
+    Ok(Response::redirect_temporary_lossy(
+        format!("gemini://{}{}/{}/log/{}", host, port, path, branch).as_str(),
+    ))
+}
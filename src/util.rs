@@ -1,11 +1,91 @@
+use directories::ProjectDirs;
+use glob::Pattern;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs, path::Path};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+const CONFIG_NAME: &str = "gemini-git-browser";
+
+/// Settings only read at startup, which need a restart to change.
+const RESTART_KEYS: [&str; 15] = [
+    "port",
+    "bind",
+    "cert",
+    "key",
+    "timeout",
+    "max_git_tasks",
+    "cache_size",
+    "max_downloads",
+    "download_timeout",
+    "titan",
+    "titan_port",
+    "http",
+    "http_port",
+    "spartan",
+    "spartan_port",
+];
+
+/// Settings naming client certificates and who they belong to, whose values
+/// are left out of the changes logged on reload.
+const CERTIFICATE_KEYS: [&str; 2] = ["certificates", "users"];
+
+/// Schemes of the links kept in HTML pages.
+const HTML_LINK_SCHEMES: [&str; 5] = ["gemini", "http", "https", "gopher", "mailto"];
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Allowed,
+    CertificateRequired,
+    NotAuthorized,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub title: String,
     pub allowed: Vec<String>,
+    pub denied: Vec<String>,
+    pub private: Vec<String>,
+    pub repo_dir: String,
+    pub port: u16,
+    pub bind: Vec<String>,
+    pub cert: String,
+    pub key: String,
+    pub timeout: u64,
+    pub max_git_tasks: usize,
+    pub cache_size: usize,
+    pub metrics: bool,
+    pub max_blob_size: usize,
+    pub max_downloads: usize,
+    pub download_timeout: u64,
+    pub templates: String,
+    pub dev_templates: bool,
+    pub clone_urls: Vec<String>,
+    pub discover: bool,
+    pub working_tree_status: bool,
+    pub reload_interval: u64,
+    pub watch_repo_dir: bool,
+    pub titan: bool,
+    pub titan_port: u16,
+    pub editors: Vec<String>,
+    pub http: bool,
+    pub http_port: u16,
+    pub spartan: bool,
+    pub spartan_port: u16,
+    // Tables have to come after plain values to be written as TOML.
+    pub ns_clone_urls: HashMap<String, Vec<String>>,
+    pub certificates: HashMap<String, String>,
+    pub users: HashMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub discovered: Vec<String>,
 }
 
 impl ::std::default::Default for Config {
@@ -13,17 +93,442 @@ impl ::std::default::Default for Config {
         Self {
             title: String::from(""),
             allowed: vec![],
+            denied: vec![],
+            private: vec![],
+            certificates: HashMap::new(),
+            users: HashMap::new(),
+            repo_dir: String::from(""),
+            port: 1965,
+            bind: vec![String::from("0.0.0.0")],
+            cert: String::from("cert/cert.pem"),
+            key: String::from("cert/key.pem"),
+            timeout: 10,
+            max_git_tasks: 8,
+            cache_size: 1000,
+            metrics: false,
+            max_blob_size: 1024 * 1024,
+            max_downloads: 16,
+            download_timeout: 600,
+            templates: String::from(""),
+            dev_templates: false,
+            clone_urls: vec![],
+            ns_clone_urls: HashMap::new(),
+            discover: false,
+            working_tree_status: false,
+            reload_interval: 5,
+            watch_repo_dir: false,
+            titan: false,
+            titan_port: 1966,
+            editors: vec![],
+            http: false,
+            http_port: 8080,
+            spartan: false,
+            spartan_port: 300,
+            discovered: vec![],
         }
     }
 }
 
 impl Config {
+    /// Reads the config file at `path`, or the default `gemini-git-browser.toml`,
+    /// and applies environment variable overrides without validating.
+    pub fn read(path: Option<&str>) -> Result<Config, Box<dyn Error>> {
+        if let Some(path) = path {
+            if !Path::new(path).is_file() {
+                return Err(format!("Config file `{}` does not exist", path).into());
+            }
+        }
+
+        let path = Config::path(path)?;
+        let mut config: Config = confy::load_path(&path)
+            .map_err(|err| format!("Error reading {}: {}", path.display(), err))?;
+
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    /// Returns `path`, or the default `gemini-git-browser.toml` in the user's
+    /// config directory.
+    pub fn path(path: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+        match path {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => {
+                let dirs = ProjectDirs::from("rs", "", CONFIG_NAME)
+                    .ok_or("Could not find the user's config directory")?;
+
+                Ok(dirs.config_dir().join(format!("{}.toml", CONFIG_NAME)))
+            }
+        }
+    }
+
+    /// Describes each setting that differs in `new`, noting the ones that only
+    /// take effect after a restart.
+    pub fn changes(&self, new: &Config) -> Vec<String> {
+        let (old, new) = match (serde_json::to_value(self), serde_json::to_value(new)) {
+            (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) => (old, new),
+            _ => return vec![],
+        };
+
+        old.iter()
+            .filter(|(key, value)| new.get(*key) != Some(value))
+            .map(|(key, value)| {
+                let change = match CERTIFICATE_KEYS.contains(&key.as_str()) {
+                    true => format!("`{}` changed", key),
+                    false => format!("`{}` changed from {} to {}", key, value, new[key]),
+                };
+
+                match RESTART_KEYS.contains(&key.as_str()) {
+                    true => format!("{}, restart the server to apply it", change),
+                    false => change,
+                }
+            })
+            .collect()
+    }
+
+    fn apply_env(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Ok(repo_dir) = env::var("REPO_DIR") {
+            self.repo_dir = repo_dir;
+        }
+
+        if let Ok(port) = env::var("GEMINI_PORT") {
+            self.port = port
+                .parse::<u16>()
+                .map_err(|_| format!("GEMINI_PORT must be a port number, got `{}`", port))?;
+        }
+
+        if let Ok(bind) = env::var("GEMINI_BIND") {
+            self.bind = bind.split(',').map(|b| String::from(b.trim())).collect();
+        }
+
+        if let Ok(cert) = env::var("GEMINI_CERT") {
+            self.cert = cert;
+        }
+
+        if let Ok(key) = env::var("GEMINI_KEY") {
+            self.key = key;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let mut errors = vec![];
+
+        if self.repo_dir.is_empty() {
+            errors.push(String::from(
+                "repo_dir is not set, set it in the config file or with the REPO_DIR environment variable",
+            ));
+        } else if !Path::new(&self.repo_dir).is_dir() {
+            errors.push(format!("repo_dir `{}` is not a directory", self.repo_dir));
+        }
+
+        if self.bind.is_empty() {
+            errors.push(String::from("bind must contain at least one address"));
+        }
+
+        for bind in &self.bind {
+            if let Err(err) = parse_listen_addr(bind, self.port) {
+                errors.push(err);
+            }
+        }
+
+        for (fingerprint, user) in &self.certificates {
+            if !self.users.contains_key(user) {
+                errors.push(format!(
+                    "certificate `{}` belongs to unknown user `{}`",
+                    fingerprint, user
+                ));
+            }
+        }
+
+        for editor in &self.editors {
+            if !self.users.contains_key(editor) {
+                errors.push(format!("editor `{}` is not a user", editor));
+            }
+        }
+
+        let listeners = [
+            ("port", true, self.port),
+            ("titan_port", self.titan, self.titan_port),
+            ("http_port", self.http, self.http_port),
+            ("spartan_port", self.spartan, self.spartan_port),
+        ];
+        let listeners = listeners
+            .iter()
+            .filter(|(_, enabled, _)| *enabled)
+            .collect::<Vec<_>>();
+
+        for (i, (name, _, port)) in listeners.iter().enumerate() {
+            for (other, _, _) in listeners[..i].iter().filter(|(_, _, other)| other == port) {
+                errors.push(format!("{} must differ from {}", name, other));
+            }
+        }
+
+        let rules = self
+            .allowed
+            .iter()
+            .chain(self.denied.iter())
+            .chain(self.private.iter())
+            .chain(self.users.values().flatten());
+
+        for rule in rules {
+            for segment in rule.trim_end_matches('/').split('/') {
+                if let Err(err) = Pattern::new(segment) {
+                    errors.push(format!("rule `{}` is not a valid pattern: {}", rule, err));
+                    break;
+                }
+            }
+        }
+
+        if !Path::new(&self.cert).is_file() {
+            errors.push(format!("TLS certificate `{}` does not exist", self.cert));
+        }
+
+        if !Path::new(&self.key).is_file() {
+            errors.push(format!("TLS key `{}` does not exist", self.key));
+        }
+
+        if self.timeout == 0 {
+            errors.push(String::from("timeout must be at least 1 second"));
+        }
+
+        if self.max_git_tasks == 0 {
+            errors.push(String::from("max_git_tasks must be at least 1"));
+        }
+
+        if self.max_downloads == 0 {
+            errors.push(String::from("max_downloads must be at least 1"));
+        }
+
+        if !self.templates.is_empty() && !Path::new(&self.templates).is_dir() {
+            errors.push(format!("templates `{}` is not a directory", self.templates));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Invalid configuration:\n  - {}", errors.join("\n  - ")).into()),
+        }
+    }
+
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        let mut addrs = vec![];
+
+        for bind in &self.bind {
+            addrs.push(parse_listen_addr(bind, self.port)?);
+        }
+
+        Ok(addrs)
+    }
+
+    /// Returns the addresses for the Titan listener, which are the `bind`
+    /// addresses with `titan_port`.
+    pub fn titan_addrs(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        self.listen_addrs_on(self.titan_port)
+    }
+
+    /// Returns the addresses for the HTTP listener, which are the `bind`
+    /// addresses with `http_port`.
+    pub fn http_addrs(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        self.listen_addrs_on(self.http_port)
+    }
+
+    /// Returns the addresses for the Spartan listener, which are the `bind`
+    /// addresses with `spartan_port`.
+    pub fn spartan_addrs(&self) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        self.listen_addrs_on(self.spartan_port)
+    }
+
+    fn listen_addrs_on(&self, port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+        let mut addrs = self.listen_addrs()?;
+
+        for addr in addrs.iter_mut() {
+            addr.set_port(port);
+        }
+
+        Ok(addrs)
+    }
+
+    /// Checks a namespace or repo path against the `allowed`, `private` and
+    /// `denied` rules. A path is served if it matches an allowed or private rule
+    /// or a discovered repo, or is a namespace such a rule could match repos in
+    /// or that contains a discovered repo, unless a denied rule matches the path
+    /// or one of its namespaces. Private paths also need `check_cert`.
     pub fn check_dir(&self, path: &str) -> bool {
-        let path_str = String::from(path);
-        self.allowed.contains(&path_str)
+        let path = path.trim_matches('/');
+        let segments = path.split('/').collect::<Vec<&str>>();
+
+        let denied = (1..=segments.len()).any(|depth| {
+            self.denied
+                .iter()
+                .any(|rule| rule_matches(rule, &segments[..depth]))
+        });
+
+        if denied {
+            return false;
+        }
+
+        let discovered = self.discovered.iter().any(|repo| {
+            repo == path || (repo.starts_with(path) && repo[path.len()..].starts_with('/'))
+        });
+
+        if discovered {
+            return true;
+        }
+
+        self.allowed
+            .iter()
+            .chain(self.private.iter())
+            .any(|rule| rule_matches(rule, &segments) || rule_contains(rule, &segments))
+    }
+
+    /// Checks whether the client certificate, given by its SHA-256
+    /// fingerprint, may access a path. Paths matching a `private` rule, or
+    /// inside a namespace that does, need a certificate mapped to a user in
+    /// `certificates` whose rules in `users` match the path, or could match
+    /// repos inside it.
+    pub fn check_cert(&self, path: &str, fingerprint: Option<&str>) -> Access {
+        let path = path.trim_matches('/');
+        let segments = path.split('/').collect::<Vec<&str>>();
+
+        let matches_path = |rules: &[String]| {
+            (1..=segments.len()).any(|depth| {
+                rules
+                    .iter()
+                    .any(|rule| rule_matches(rule, &segments[..depth]))
+            })
+        };
+
+        if !matches_path(&self.private) {
+            return Access::Allowed;
+        }
+
+        let fingerprint = match fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Access::CertificateRequired,
+        };
+
+        let user = self
+            .cert_user(fingerprint)
+            .and_then(|user| self.users.get(user));
+
+        match user {
+            Some(rules)
+                if matches_path(rules)
+                    || rules.iter().any(|rule| rule_contains(rule, &segments)) =>
+            {
+                Access::Allowed
+            }
+            _ => Access::NotAuthorized,
+        }
+    }
+
+    /// Returns the user a client certificate, given by its SHA-256
+    /// fingerprint, is mapped to in `certificates`.
+    pub fn cert_user(&self, fingerprint: &str) -> Option<&String> {
+        self.certificates
+            .iter()
+            .find(|(cert, _)| cert.replace(':', "").eq_ignore_ascii_case(fingerprint))
+            .map(|(_, user)| user)
+    }
+
+    /// Checks whether the client certificate, given by its SHA-256
+    /// fingerprint, may upload files to a repo with Titan. It has to belong to
+    /// a user listed in `editors` whose rules in `users` match the repo or one
+    /// of its namespaces.
+    pub fn check_editor(&self, path: &str, fingerprint: Option<&str>) -> Access {
+        let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+
+        let fingerprint = match fingerprint {
+            Some(fingerprint) => fingerprint,
+            None => return Access::CertificateRequired,
+        };
+
+        let rules = self
+            .cert_user(fingerprint)
+            .filter(|user| self.editors.contains(user))
+            .and_then(|user| self.users.get(user));
+
+        let matches = rules.is_some_and(|rules| {
+            (1..=segments.len()).any(|depth| {
+                rules
+                    .iter()
+                    .any(|rule| rule_matches(rule, &segments[..depth]))
+            })
+        });
+
+        match matches {
+            true => Access::Allowed,
+            false => Access::NotAuthorized,
+        }
+    }
+
+    pub fn get_clone_urls(&self, ns: &str, repo: &str) -> Vec<String> {
+        let templates = self.ns_clone_urls.get(ns).unwrap_or(&self.clone_urls);
+        let name = repo.trim_end_matches(".git");
+
+        templates
+            .iter()
+            .map(|template| {
+                // Top-level repos have no namespace, so its segment is left out
+                // along with the slash after it.
+                let template = match ns.is_empty() {
+                    true => template.replace("{ns}/", ""),
+                    false => template.clone(),
+                };
+
+                template
+                    .replace("{ns}", ns)
+                    .replace("{repo}", repo)
+                    .replace("{name}", name)
+            })
+            .collect::<Vec<String>>()
     }
 }
 
+/// Matches path segments against an `allowed` or `denied` rule. `*`, `?` and
+/// `[...]` match within a single segment, and a trailing `/` matches the
+/// namespace itself and everything below it.
+fn rule_matches(rule: &str, segments: &[&str]) -> bool {
+    match rule.strip_suffix('/') {
+        Some(prefix) => {
+            let prefix = prefix.split('/').collect::<Vec<&str>>();
+            segments.len() >= prefix.len() && segments_match(&prefix, &segments[..prefix.len()])
+        }
+        None => segments_match(&rule.split('/').collect::<Vec<&str>>(), segments),
+    }
+}
+
+/// Checks whether a rule can match paths below the namespace in `segments`.
+fn rule_contains(rule: &str, segments: &[&str]) -> bool {
+    let rule = rule.trim_end_matches('/').split('/').collect::<Vec<&str>>();
+
+    rule.len() > segments.len() && segments_match(&rule[..segments.len()], segments)
+}
+
+fn segments_match(rule: &[&str], segments: &[&str]) -> bool {
+    rule.len() == segments.len()
+        && rule.iter().zip(segments).all(|(rule_segment, segment)| {
+            Pattern::new(rule_segment)
+                .map(|pattern| pattern.matches(segment))
+                .unwrap_or(false)
+        })
+}
+
+/// Parses a bind address, either a bare IPv4/IPv6 address which listens on
+/// `port`, or a full socket address such as `[::1]:1966`.
+fn parse_listen_addr(addr: &str, port: u16) -> Result<SocketAddr, String> {
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        return Ok(socket_addr);
+    }
+
+    addr.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|_| format!("bind address `{}` is not a valid IP address", addr))
+}
+
 pub fn get_dirs(dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
     let ns_repos = fs::read_dir(Path::new(dir))?
         .filter_map(|f| {
@@ -31,7 +536,7 @@ pub fn get_dirs(dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
                 if d.path().is_dir() {
                     d.path()
                         .file_name()
-                        .and_then(|n| n.to_str().map(|s| String::from(s)))
+                        .and_then(|n| n.to_str().map(String::from))
                 } else {
                     None
                 }
@@ -42,6 +547,27 @@ pub fn get_dirs(dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(ns_repos)
 }
 
+/// Returns the hex encoded SHA-256 fingerprint of a DER encoded certificate.
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+}
+
+/// Checks that a decoded request path segment names a single entry inside
+/// its parent directory, so it can't step out of the repo dir.
+pub fn is_safe_segment(segment: &str) -> bool {
+    !matches!(segment, "" | "." | "..") && !segment.contains(['/', '\\', '\0'])
+}
+
+/// Checks whether `dir` looks like a bare git repository, or a working tree
+/// with a `.git` dir or file.
+pub fn is_repo_dir(dir: &str) -> bool {
+    let dir = Path::new(dir);
+    dir.join(".git").exists() || (dir.join("HEAD").is_file() && dir.join("objects").is_dir())
+}
+
 pub fn get_files(dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
     let ns_repos = fs::read_dir(Path::new(dir))?
         .filter_map(|f| {
@@ -49,7 +575,7 @@ pub fn get_files(dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
                 if d.path().is_file() {
                     d.path()
                         .file_name()
-                        .and_then(|n| n.to_str().map(|s| String::from(s)))
+                        .and_then(|n| n.to_str().map(String::from))
                 } else {
                     None
                 }
@@ -95,7 +621,7 @@ pub fn md_to_gemtext(contents: &str) -> Result<String, Box<dyn Error>> {
             changed_line = str::replace(&changed_line, "`", "");
         }
 
-        for link in link_regex.captures_iter(&line) {
+        for link in link_regex.captures_iter(line) {
             let link_text = replace_link_text_chars.replace_all(&link["text"], "");
             let link_text = replace_chars.replace_all(&link_text, "");
             let link_uri = replace_chars.replace_all(&link["link"], "");
@@ -105,7 +631,7 @@ pub fn md_to_gemtext(contents: &str) -> Result<String, Box<dyn Error>> {
                 is_anchor_link = true;
             }
 
-            let replacement_text = match link_text.len() > 0 && !is_anchor_link {
+            let replacement_text = match !link_text.is_empty() && !is_anchor_link {
                 true => format!("{}[{}]", &link_text, count),
                 false => link_text.to_string(),
             };
@@ -121,7 +647,7 @@ pub fn md_to_gemtext(contents: &str) -> Result<String, Box<dyn Error>> {
                     text: link_text.to_string(),
                 });
 
-                count = count + 1;
+                count += 1;
             }
         }
         // let new_line = String::from(&changed_line);
@@ -144,10 +670,112 @@ pub fn md_to_gemtext(contents: &str) -> Result<String, Box<dyn Error>> {
                 changed_line, l.link, l.number, l.text
             );
         }
-        lines.push(String::from(changed_line));
+        lines.push(changed_line);
     }
 
     let contents = lines.join("\r\n");
 
     Ok(contents)
 }
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Checks that a link can be followed from an HTML page, which is the case
+/// for relative ones and those using a scheme in `HTML_LINK_SCHEMES`. Others,
+/// such as `javascript:` links, would run in the page.
+fn is_html_link(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => HTML_LINK_SCHEMES
+            .iter()
+            .any(|allowed| scheme.eq_ignore_ascii_case(allowed)),
+        _ => true,
+    }
+}
+
+/// Converts gemtext to HTML, one element per line. Consecutive list items are
+/// grouped into a list, and preformatted blocks are kept as they are.
+pub fn gemtext_to_html(gemtext: &str) -> String {
+    let mut html = String::new();
+    let mut preformatted = false;
+    let mut list = false;
+
+    for line in gemtext.lines() {
+        if preformatted {
+            match line.starts_with("```") {
+                true => {
+                    html.push_str("</pre>\n");
+                    preformatted = false;
+                }
+                false => {
+                    html.push_str(&escape_html(line));
+                    html.push('\n');
+                }
+            }
+            continue;
+        }
+
+        if list && !line.starts_with("* ") {
+            html.push_str("</ul>\n");
+            list = false;
+        }
+
+        let element = if line.starts_with("```") {
+            preformatted = true;
+            String::from("<pre>")
+        } else if let Some(link) = line.strip_prefix("=>") {
+            let link = link.trim();
+            let (url, text) = match link.split_once(char::is_whitespace) {
+                Some((url, text)) => (url, text.trim()),
+                None => (link, link),
+            };
+
+            match is_html_link(url) {
+                true => format!(
+                    "<p><a href=\"{}\">{}</a></p>",
+                    escape_html(url),
+                    escape_html(text)
+                ),
+                false => format!("<p>{}</p>", escape_html(text)),
+            }
+        } else if let Some(heading) = line.strip_prefix("###") {
+            format!("<h3>{}</h3>", escape_html(heading.trim()))
+        } else if let Some(heading) = line.strip_prefix("##") {
+            format!("<h2>{}</h2>", escape_html(heading.trim()))
+        } else if let Some(heading) = line.strip_prefix('#') {
+            format!("<h1>{}</h1>", escape_html(heading.trim()))
+        } else if let Some(item) = line.strip_prefix("* ") {
+            let start = match list {
+                true => "",
+                false => "<ul>\n",
+            };
+            list = true;
+
+            format!("{}<li>{}</li>", start, escape_html(item))
+        } else if let Some(quote) = line.strip_prefix('>') {
+            format!("<blockquote>{}</blockquote>", escape_html(quote.trim()))
+        } else if line.trim().is_empty() {
+            continue;
+        } else {
+            format!("<p>{}</p>", escape_html(line))
+        };
+
+        html.push_str(&element);
+        html.push('\n');
+    }
+
+    if list {
+        html.push_str("</ul>\n");
+    }
+
+    if preformatted {
+        html.push_str("</pre>\n");
+    }
+
+    html
+}